@@ -1,10 +1,44 @@
-use std::ops::Deref;
+use std::cmp::Ordering;
+use std::ops::{ControlFlow, Deref};
 use std::cell::{Cell, UnsafeCell};
+use std::collections::LinkedList;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::panic::{RefUnwindSafe, UnwindSafe};
 use std::ptr::NonNull;
 
 /// The `Rc`-like handle owning a value,
 /// which may have at most one weak reference in a list.
+///
+/// Like `Rc`, it is neither `Send` nor `Sync`.
+///
+/// ```compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+/// assert_send::<weak_list::Handle<i32>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<weak_list::Handle<i32>>();
+/// ```
+///
+/// It can only be created by a list, or by `Handle::new` without a list.
+///
+/// ```compile_fail,E0451
+/// let h: weak_list::Handle<i32> = weak_list::Handle {
+///     cur: std::ptr::NonNull::dangling(),
+/// };
+/// ```
 pub struct Handle<T> {
+    // Keep it the only field, so `Option<Handle<T>>` is pointer-sized.
+    cur: NonNull<Node<T>>,
+}
+
+/// The `rc::Weak`-like standalone weak reference to a value,
+/// created by `Handle::downgrade`.
+///
+/// Unlike weak references in a `WeakList`, it keeps the space of the value
+/// from being freed, until all `Weak`s to it are dropped.
+pub struct Weak<T> {
     cur: NonNull<Node<T>>,
 }
 
@@ -13,32 +47,136 @@ pub struct Handle<T> {
 /// Unlike `Vec<Weak<T>>`, once a weak reference in `WeakList` died,
 /// immediately, it will be removed from the list and both the space of value
 /// and its weak reference will be freed completely.
+///
+/// It is neither `Send` nor `Sync`.
+///
+/// ```compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+/// assert_send::<weak_list::WeakList<i32>>();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<weak_list::WeakList<i32>>();
+/// ```
 pub struct WeakList<T> {
-    head: Box<UnsafeCell<NodePtr<T>>>,
+    /// Allocated separately, so `swap_with` can swap it with nodes untouched.
+    head: Cell<NonNull<UnsafeCell<NodePtr<T>>>>,
+    pool: UnsafeCell<Vec<NodeSlot<T>>>,
+    order: InsertOrder,
+    max_len: Option<usize>,
+}
+
+/// An iterator over the values in a `WeakList`, upgrading them one by one.
+///
+/// It always holds a `Handle` to the next value, so yielded handles can be
+/// dropped or detached freely during the iteration.
+/// If the next value is detached or moved to another list before reached,
+/// the iteration stops there.
+pub struct Iter<'a, T: 'a> {
+    list: &'a WeakList<T>,
+    next: Option<Handle<T>>,
+}
+
+/// A builder of `WeakList`, created by `WeakList::builder`.
+///
+/// It keeps `Handle`s to all pushed values, and returns them along with the
+/// list in `finish`, so values cannot be dropped accidentally meanwhile.
+pub struct ListBuilder<T> {
+    list: WeakList<T>,
+    handles: Vec<Handle<T>>,
+}
+
+/// A `Handle` projected to a part of its value, created by `Handle::map`.
+///
+/// It keeps the whole value alive, and dereferences to the part.
+pub struct HandleMap<T, U: ?Sized> {
+    handle: Handle<T>,
+    ptr: NonNull<U>,
+}
+
+/// The memory usage of a `WeakList`, returned by `WeakList::capacity_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityReport {
+    /// The number of values in the list.
+    pub live: usize,
+    /// The number of pre-allocated spaces in the pool.
+    pub pooled: usize,
+    /// The size in bytes of the space for each value.
+    pub node_size: usize,
+}
+
+/// The error returned by `Handle::try_clone` when there are too many
+/// `Handle`s to a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CloneError;
+
+impl ::std::fmt::Display for CloneError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("too many handles to a value")
+    }
+}
+
+impl ::std::error::Error for CloneError {}
+
+/// Where `WeakList::new_elem` puts new values, chosen by `WeakList::with_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOrder {
+    /// Push to the front of the list. This is the default.
+    Front,
+    /// Push to the back of the list, in O(n) time since the list does not
+    /// track its last value.
+    Back,
 }
 
 type NodePtr<T> = Option<NonNull<Node<T>>>;
 
+/// An allocated but uninitialized node, reserved for later `new_elem`s.
+type NodeSlot<T> = Box<MaybeUninit<Node<T>>>;
+
 struct Node<T> {
-    value: T,
+    value: ManuallyDrop<T>,
     strong_count: Cell<usize>,
+    /// The number of `Weak`s, plus one if any `Handle` exists.
+    weak_count: Cell<usize>,
     prev_next: Cell<Option<NonNull<NodePtr<T>>>>,
     next: UnsafeCell<NodePtr<T>>,
+    /// The head of the list containing this node, if linked.
+    list: Cell<Option<NonNull<NodePtr<T>>>>,
 }
 
 impl<T> Node<T> {
-    unsafe fn new_before(next_ptr: NodePtr<T>, value: T) -> NonNull<Node<T>> {
-        let b = Box::new(Node {
-            value,
+    unsafe fn new_in(slot: NodeSlot<T>, value: T) -> NonNull<Node<T>> {
+        // `MaybeUninit<Node<T>>` has the same layout as `Node<T>`, so the node
+        // can be freed as a `Box<Node<T>>` later.
+        let p = Box::into_raw(slot) as *mut Node<T>;
+        p.write(Node {
+            value: ManuallyDrop::new(value),
             strong_count: Cell::new(0), // Begin at 0
+            weak_count: Cell::new(1),
             prev_next: Cell::new(None),
-            next: UnsafeCell::new(next_ptr),
+            next: UnsafeCell::new(None),
+            list: Cell::new(None),
         });
+        NonNull::new_unchecked(p)
+    }
+
+    /// Link an unlinked node into `place`, which is either the head of a list
+    /// or the `next` of a linked node, in the list of head `list`.
+    unsafe fn link_at(
+        this: NonNull<Self>,
+        mut place: NonNull<NodePtr<T>>,
+        list: NonNull<NodePtr<T>>,
+    ) {
+        let node = this.as_ref();
+        let next_ptr = *place.as_ref();
         if let Some(next) = next_ptr {
-            let rev_ptr = NonNull::new_unchecked(b.next.get());
-            next.as_ref().prev_next.set(Some(rev_ptr));
+            next.as_ref().prev_next.set(Some(NonNull::new_unchecked(node.next.get())));
         }
-        NonNull::new_unchecked(Box::into_raw(b))
+        *node.next.get() = next_ptr;
+        node.prev_next.set(Some(place));
+        node.list.set(Some(list));
+        *place.as_mut() = Some(this);
     }
 
     unsafe fn unlink(&self) {
@@ -47,17 +185,72 @@ impl<T> Node<T> {
             if let Some(next) = *self.next.get() { // Has next
                 next.as_ref().prev_next.set(Some(prev_next));
             }
+            *self.next.get() = None; // Never point to a node we don't own.
+            self.list.set(None);
+        }
+    }
+
+    /// Get the strong count without a reference to the whole node, since the
+    /// value may be borrowed mutably by its destructor meanwhile.
+    unsafe fn strong_count<'a>(this: NonNull<Self>) -> &'a Cell<usize> {
+        &*::std::ptr::addr_of!((*this.as_ptr()).strong_count)
+    }
+
+    /// The same as `strong_count`, for the weak count.
+    unsafe fn weak_count<'a>(this: NonNull<Self>) -> &'a Cell<usize> {
+        &*::std::ptr::addr_of!((*this.as_ptr()).weak_count)
+    }
+
+    /// Add one weak count. Aborts if it overflows, like `Rc`.
+    unsafe fn inc_weak(this: NonNull<Self>) {
+        let count = Self::weak_count(this);
+        match count.get().checked_add(1) {
+            Some(n) => count.set(n),
+            None => ::std::process::abort(),
+        }
+    }
+
+    /// Release one weak count, and free the node if there is none left.
+    unsafe fn release_weak(this: NonNull<Self>) {
+        let count = Self::weak_count(this);
+        match count.get() {
+            1 => drop(Box::from_raw(this.as_ptr())),
+            x => count.set(x - 1),
         }
     }
 }
 
 impl<T> Handle<T> {
+    /// Create a `Handle` owning `value`, which is not in any list, like
+    /// `Rc::new`. It can be pushed into a list later by `WeakList::attach`.
+    pub fn new(value: T) -> Self {
+        unsafe {
+            let node = Node::new_in(Box::new(MaybeUninit::uninit()), value);
+            Self::from_raw_node(node)
+        }
+    }
+
+    /// Aborts if the strong count overflows, like `Rc`.
+    #[inline]
     unsafe fn from_raw_node(node: NonNull<Node<T>>) -> Self {
-        let count = &node.as_ref().strong_count;
-        count.set(count.get() + 1);
+        let count = Node::strong_count(node);
+        match count.get().checked_add(1) {
+            Some(n) => count.set(n),
+            None => ::std::process::abort(),
+        }
         Handle { cur: node }
     }
 
+    /// The same as `clone`, but returns an error instead of aborting the
+    /// process when the number of `Handle`s overflows `usize`.
+    pub fn try_clone(this: &Self) -> Result<Self, CloneError> {
+        unsafe {
+            let count = &this.cur.as_ref().strong_count;
+            count.set(count.get().checked_add(1).ok_or(CloneError)?);
+            Ok(Handle { cur: this.cur })
+        }
+    }
+
     /// Detach the value from the list.
     /// It removes and frees the weak reference of it in the list immediately
     /// (if exists).
@@ -65,6 +258,23 @@ impl<T> Handle<T> {
         unsafe { this.cur.as_ref().unlink(); }
     }
 
+    /// Check if the value has a weak reference in a list.
+    pub fn is_linked(this: &Self) -> bool {
+        unsafe { this.cur.as_ref().prev_next.get().is_some() }
+    }
+
+    /// Check if the value is the first one in `list`.
+    pub fn is_first_in(this: &Self, list: &WeakList<T>) -> bool {
+        unsafe { *list.head().get() == Some(this.cur) }
+    }
+
+    /// Check if the value is the last one in `list`.
+    ///
+    /// The tail is not tracked, so it walks the whole list in O(n).
+    pub fn is_last_in(this: &Self, list: &WeakList<T>) -> bool {
+        list.last_node() == Some(this.cur)
+    }
+
     /// Try unwrap the value if `this` is the only `Handle` to it.
     ///
     /// If it success, the weak reference of it in the list (if exists) will
@@ -72,60 +282,362 @@ impl<T> Handle<T> {
     /// Otherwise, `this` will be returned back with nothing happened.
     pub fn try_unwrap(this: Self) -> Result<T, Self> {
         unsafe {
-            Self::detach(&this);
-            match this.cur.as_ref().strong_count.get() {
-                1 => {
-                    let b = Box::from_raw(this.cur.as_ptr());
+            match Self::unwrap_if_unique(this.cur, |v| ManuallyDrop::take(v)) {
+                Some(value) => {
                     ::std::mem::forget(this);
-                    Ok(b.value)
+                    Ok(value)
                 }
-                _ => Err(this),
+                None => Err(this),
+            }
+        }
+    }
+
+    /// If the `Handle` to `node` is the only one, detach the value, pass it
+    /// to `f` to be moved out or dropped, then release the node.
+    ///
+    /// The strong count is left 0 on success, so the caller must forget the
+    /// `Handle`. Otherwise, it returns `None` with nothing happened.
+    unsafe fn unwrap_if_unique<R, F>(node: NonNull<Node<T>>, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut ManuallyDrop<T>) -> R,
+    {
+        let count = Node::strong_count(node);
+        debug_assert!(count.get() >= 1, "strong count underflow");
+        if count.get() != 1 {
+            return None;
+        }
+        // The destructor of the value may walk the list or upgrade
+        // `Weak`s, so it must be unreachable before that.
+        node.as_ref().unlink();
+        count.set(0);
+        let ret = f(&mut (*node.as_ptr()).value);
+        Node::release_weak(node);
+        Some(ret)
+    }
+
+    /// Create a new `Weak` to the value.
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        unsafe { Node::inc_weak(this.cur); }
+        Weak { cur: this.cur }
+    }
+
+    /// Get the number of `Handle`s to the value.
+    pub fn strong_count(this: &Self) -> usize {
+        unsafe { this.cur.as_ref().strong_count.get() }
+    }
+
+    /// Get the number of `Weak`s to the value.
+    ///
+    /// The weak reference in a list is not counted.
+    pub fn weak_count(this: &Self) -> usize {
+        unsafe { this.cur.as_ref().weak_count.get() - 1 }
+    }
+
+    /// Get the strong count and a reference to the value in one call.
+    ///
+    /// It is only a convenience for `(Handle::strong_count(this), &*this)`.
+    #[inline]
+    pub fn snapshot(this: &Self) -> (usize, &T) {
+        (Self::strong_count(this), this)
+    }
+
+    /// Project `this` to a part of its value, like a field.
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(this: Self, f: F) -> HandleMap<T, U> {
+        let ptr = NonNull::from(f(&this));
+        HandleMap { handle: this, ptr }
+    }
+
+    /// The same as `map`, but `f` may fail, in which case `this` is returned
+    /// back along with the error.
+    pub fn try_map<U: ?Sized, E, F>(this: Self, f: F) -> Result<HandleMap<T, U>, (Self, E)>
+    where
+        F: FnOnce(&T) -> Result<&U, E>,
+    {
+        match f(&this) {
+            Ok(r) => {
+                let ptr = NonNull::from(r);
+                Ok(HandleMap { handle: this, ptr })
+            }
+            Err(e) => Err((this, e)),
+        }
+    }
+
+    /// Call `f` with a reference to the value.
+    #[inline]
+    pub fn with<R, F: FnOnce(&T) -> R>(this: &Self, f: F) -> R {
+        f(this)
+    }
+
+    /// The same as `modify`.
+    #[inline]
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(this: &mut Self, f: F) -> Option<R> {
+        Self::modify(this, f)
+    }
+
+    /// Get a mutable reference to the value, if `this` is the only reference
+    /// to it.
+    ///
+    /// Since a list or a `Weak` can be upgraded to another `Handle` anytime,
+    /// it returns `None` if the value is in a list or there are any `Weak`s.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        unsafe {
+            let node = this.cur.as_ref();
+            if node.strong_count.get() == 1
+                && node.weak_count.get() == 1
+                && node.prev_next.get().is_none() {
+                Some(&mut *(*this.cur.as_ptr()).value)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Call `f` with a mutable reference to the value, if `this` is the only
+    /// reference to it. See `get_mut` for details.
+    pub fn modify<R, F: FnOnce(&mut T) -> R>(this: &mut Self, f: F) -> Option<R> {
+        Self::get_mut(this).map(f)
+    }
+
+    /// Drop the value if `this` is the only `Handle` to it, the same as
+    /// dropping the last `Handle`.
+    ///
+    /// The space of the value is kept until all `Weak`s to it are dropped,
+    /// and they will fail to upgrade.
+    /// Otherwise, `this` will be returned back with nothing happened.
+    pub fn drop_value(this: Self) -> Result<(), Self> {
+        match Self::strong_count(&this) {
+            1 => {
+                drop(this);
+                Ok(())
             }
+            _ => Err(this),
         }
     }
+
+    /// The same as `try_unwrap`, but moves the value into a new `Box`.
+    pub fn try_into_box(this: Self) -> Result<Box<T>, Self> {
+        Self::try_unwrap(this).map(Box::new)
+    }
+
+    /// Get an id of the value, which is unique among all living values.
+    ///
+    /// Ids may be reused after the value is freed.
+    pub fn id(this: &Self) -> usize {
+        this.cur.as_ptr() as usize
+    }
+
+    /// Hash the id of the value, for use in low-level hash tables.
+    ///
+    /// It hashes the address, not the value, with
+    /// `std::collections::hash_map::DefaultHasher::new()`, so it is the same
+    /// for all `Handle`s to a value, but may change across Rust versions.
+    pub fn raw_hash(this: &Self) -> u64 {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        Self::id(this).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Check if two `Handle`s point to the same value.
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.cur == other.cur
+    }
+
+    /// Check if `this` and `weak` point to the same value.
+    pub fn ptr_eq_weak(this: &Self, weak: &Weak<T>) -> bool {
+        this.cur == weak.cur
+    }
+
+    /// Check if two `Handle`s point to the same value, or to equal values.
+    ///
+    /// The values are compared only if the pointers differ, so `PartialEq`
+    /// is never invoked for two `Handle`s to the same value.
+    pub fn same_or_equal(this: &Self, other: &Self) -> bool
+    where
+        T: PartialEq,
+    {
+        Self::ptr_eq(this, other) || **this == **other
+    }
+
+    /// Check if two `Handle`s point to the same value, or `f` returns true
+    /// for their values.
+    ///
+    /// Like `same_or_equal`, `f` is not called if the pointers are the same.
+    pub fn eq_by<F: Fn(&T, &T) -> bool>(this: &Self, other: &Self, f: F) -> bool {
+        Self::ptr_eq(this, other) || f(this, other)
+    }
 }
 
 impl<T> Clone for Handle<T> {
+    /// Aborts if the number of `Handle`s overflows `usize`, like `Rc`.
+    /// See `Handle::try_clone` for a fallible version.
+    #[inline]
     fn clone(&self) -> Self {
-        unsafe {
-            let count = &self.cur.as_ref().strong_count;
-            count.set(count.get() + 1);
-            Handle { cur: self.cur }
-        }
+        unsafe { Handle::from_raw_node(self.cur) }
+    }
+}
+
+impl<T: Default> Default for Handle<T> {
+    /// Create a `Handle` owning `T::default()`, not in any list.
+    fn default() -> Self {
+        Handle::new(T::default())
     }
 }
 
 impl<T> Deref for Handle<T> {
     type Target = T;
 
+    #[inline]
     fn deref(&self) -> &T {
         unsafe { &self.cur.as_ref().value }
     }
 }
 
+/// It forwards to `T`'s `AsRef`, so that `Handle<String>` can be passed as
+/// `impl AsRef<str>`. It conflicts with `AsRef<T>` like `Rc`'s, which is left
+/// out since `Deref` already gives `&T`.
+impl<T: AsRef<U>, U: ?Sized> AsRef<U> for Handle<T> {
+    fn as_ref(&self) -> &U {
+        (**self).as_ref()
+    }
+}
+
+/// `Handle`s are compared by value like `Rc`.
+/// Use `Handle::ptr_eq` to check whether they point to the same value.
+impl<T: PartialEq> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Handle<T> {}
+
+impl<T: PartialOrd> PartialOrd for Handle<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord> Ord for Handle<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
 impl<T> Drop for Handle<T> {
+    #[inline]
     fn drop(&mut self) {
         unsafe {
-            let count = &self.cur.as_ref().strong_count;
-            match count.get() {
-                1 => {
-                    Handle::detach(&self);
-                    drop(Box::from_raw(self.cur.as_ptr()));
-                },
-                x => count.set(x - 1),
+            if Handle::unwrap_if_unique(self.cur, |v| ManuallyDrop::drop(v)).is_none() {
+                let count = Node::strong_count(self.cur);
+                count.set(count.get() - 1);
+            }
+        }
+    }
+}
+
+impl<T> Weak<T> {
+    /// Try to get a `Handle` to the value, if it is still alive.
+    pub fn upgrade(&self) -> Option<Handle<T>> {
+        unsafe {
+            match Node::strong_count(self.cur).get() {
+                0 => None,
+                _ => Some(Handle::from_raw_node(self.cur)),
             }
         }
     }
 }
 
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        unsafe { Node::inc_weak(self.cur); }
+        Weak { cur: self.cur }
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        unsafe { Node::release_weak(self.cur); }
+    }
+}
+
 impl<T> WeakList<T> {
     /// Create an empty list.
     pub fn new() -> Self {
         WeakList {
-            head: Box::new(UnsafeCell::new(None)),
+            head: Cell::new(NonNull::from(Box::leak(Box::new(UnsafeCell::new(None))))),
+            pool: UnsafeCell::new(Vec::new()),
+            order: InsertOrder::Front,
+            max_len: None,
         }
     }
 
+    /// Create a `ListBuilder` to build a list and keep `Handle`s to its
+    /// values together.
+    pub fn builder() -> ListBuilder<T> {
+        ListBuilder { list: Self::new(), handles: Vec::new() }
+    }
+
+    /// Create an empty list whose `new_elem` pushes values in `order`.
+    pub fn with_order(order: InsertOrder) -> Self {
+        let mut list = Self::new();
+        list.order = order;
+        list
+    }
+
+    /// Create an empty list which keeps at most `n` values.
+    ///
+    /// When `new_elem` makes the list longer than `n`, the oldest value is
+    /// removed from the list. This only removes its weak reference, and the
+    /// value itself is never dropped by the eviction. Finding the length and
+    /// the oldest value takes O(n) time for each `new_elem`.
+    ///
+    /// `extend_front` and `extend_back` evict the oldest values before
+    /// pushing, so the new values are all kept if there are at most `n` of
+    /// them. Other ways of adding values like `attach` are not limited.
+    pub fn with_max_len(n: usize) -> Self {
+        let mut list = Self::new();
+        list.max_len = Some(n);
+        list
+    }
+
+    /// Create an empty list which pushes values in `order` and keeps at most
+    /// `n` of them, combining `with_order` and `with_max_len`.
+    ///
+    /// The oldest value is evicted as usual, which is the first one in the
+    /// list for `InsertOrder::Back`.
+    pub fn with_order_and_max_len(order: InsertOrder, n: usize) -> Self {
+        let mut list = Self::with_order(order);
+        list.max_len = Some(n);
+        list
+    }
+
+    /// Create an empty list with spaces for `n` values pre-allocated in its
+    /// pool, the same as calling `reserve_pool(n)` after `new`.
+    ///
+    /// The capacity never limits the length of the list.
+    pub fn with_capacity(n: usize) -> Self {
+        let list = Self::new();
+        list.reserve_pool(n);
+        list
+    }
+
+    /// Create a list of weak references of existing values, keeping their
+    /// order. See `extend_from_handles` for details.
+    ///
+    /// # Warning
+    /// `handles` are dropped when it returns. The list only holds weak
+    /// references, so values not held by other `Handle`s will be dropped and
+    /// removed from the list immediately. Pass clones of `Handle`s you keep,
+    /// like `WeakList::from_handles(v.iter().cloned())`.
+    pub fn from_handles<I: IntoIterator<Item = Handle<T>>>(handles: I) -> Self {
+        let list = Self::new();
+        list.extend_from_handles(handles);
+        list
+    }
+
     /// Wrap a value into `Handle` and push the weak reference into the list.
     ///
     /// # Warning
@@ -133,135 +645,2648 @@ impl<T> WeakList<T> {
     /// to the value. So discard the return value like `list.new_elem(value);`
     /// will cause the value being dropped and removed from `list` immediately,
     /// which is quite meaningless.
+    ///
+    /// The value is pushed to the front, or the back if the list is created
+    /// with `InsertOrder::Back`. See also `with_max_len` for eviction.
     pub fn new_elem(&self, value: T) -> Handle<T> {
         unsafe {
-            let old_first = *self.head.get();
-            let new_first = Node::new_before(old_first, value);
-            let head_place = NonNull::new_unchecked(self.head.get());
-            new_first.as_ref().prev_next.set(Some(head_place));
-            *self.head.get() = Some(new_first);
-            Handle::from_raw_node(new_first)
+            let node = self.new_node(value);
+            let place = match self.order {
+                InsertOrder::Front => self.head_place(),
+                InsertOrder::Back => self.end_place(),
+            };
+            Node::link_at(node, place, self.head_place());
+            let handle = Handle::from_raw_node(node);
+            self.evict_for(0);
+            handle
         }
     }
 
-    /// Clear the list and free spaces for all weak references.
-    ///
-    /// Note that it never cause the drop of any value.
-    /// All values existing in the `WeakList` must still be strongly
-    /// referenced by some `Handle`s outside.
-    pub fn clear(&self) {
-        self.take_all();
+    /// Remove the oldest values until `extra` more values fit in the maximum
+    /// length, or the list is empty.
+    fn evict_for(&self, extra: usize) {
+        let keep = match self.max_len {
+            Some(n) => n.saturating_sub(extra),
+            None => return,
+        };
+        let len = self.len();
+        if len <= keep {
+            return;
+        }
+        unsafe {
+            match self.order {
+                InsertOrder::Front => {
+                    let place = self.place_at(keep);
+                    while let Some(node) = *place.as_ref() {
+                        node.as_ref().unlink();
+                    }
+                }
+                InsertOrder::Back => {
+                    for _ in keep..len {
+                        (*self.head().get()).unwrap().as_ref().unlink();
+                    }
+                }
+            }
+        }
     }
 
-    /// Take a snapshot for all weak-referenced values in the `WeakList`
-    /// and upgrade them.
+    /// The same as `new_elem`, but also returns a `Weak` of the new value.
+    pub fn new_elem_weak(&self, value: T) -> (Handle<T>, Weak<T>) {
+        let handle = self.new_elem(value);
+        let weak = Handle::downgrade(&handle);
+        (handle, weak)
+    }
+
+    /// Push the weak reference of an existing value into the list.
     ///
-    /// It will not change the list.
-    pub fn upgrade_all(&self) -> Vec<Handle<T>> {
+    /// Since a value may have at most one weak reference, it is detached from
+    /// its current list first (if exists).
+    pub fn attach(&self, handle: &Handle<T>) {
+        Handle::detach(handle);
+        unsafe { Node::link_at(handle.cur, self.head_place(), self.head_place()); }
+    }
+
+    fn last_node(&self) -> NodePtr<T> {
         unsafe {
-            let mut v = vec![];
-            let mut cur = *self.head.get();
+            let mut last = None;
+            let mut cur = *self.head().get();
             while let Some(cur_node) = cur {
-                v.push(Handle::from_raw_node(cur_node));
+                last = cur;
                 cur = *cur_node.as_ref().next.get();
             }
-            v
+            last
         }
     }
 
-    /// The same as `upgrade_all`, except it clears the list before return.
-    pub fn take_all(&self) -> Vec<Handle<T>> {
-        let v = self.upgrade_all();
-        v.iter().for_each(|h| Handle::detach(&h));
-        v
+    /// Create an unlinked node of `value`, in a slot from the pool if any.
+    unsafe fn new_node(&self, value: T) -> NonNull<Node<T>> {
+        let slot = (*self.pool.get()).pop()
+            .unwrap_or_else(|| Box::new(MaybeUninit::uninit()));
+        Node::new_in(slot, value)
     }
-}
 
-impl<T> Default for WeakList<T> {
-    fn default() -> Self {
-        Self::new()
+    /// Get the place after the last node.
+    fn end_place(&self) -> NonNull<NodePtr<T>> {
+        match self.last_node() {
+            Some(last) => unsafe { NonNull::new_unchecked(last.as_ref().next.get()) },
+            None => self.head_place(),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::rc::Rc;
-    use std::cell::RefCell;
+    fn head(&self) -> &UnsafeCell<NodePtr<T>> {
+        unsafe { self.head.get().as_ref() }
+    }
 
-    struct S {
-        value: i32,
-        buf: Rc<RefCell<Vec<i32>>>,
+    /// Check if the value of `handle` is in this list.
+    fn contains_node(&self, handle: &Handle<T>) -> bool {
+        unsafe { handle.cur.as_ref().list.get() == Some(self.head_place()) }
     }
 
-    impl Drop for S {
-        fn drop(&mut self) {
-            self.buf.borrow_mut().push(self.value);
-        }
+    fn head_place(&self) -> NonNull<NodePtr<T>> {
+        unsafe { NonNull::new_unchecked(self.head().get()) }
     }
 
-    #[test]
-    fn basic_test() {
-        use std::mem::replace;
+    /// Get the place of the `index`-th node, which is the end of the list
+    /// if `index == len`.
+    ///
+    /// Panics if `index > len`.
+    fn place_at(&self, index: usize) -> NonNull<NodePtr<T>> {
+        unsafe {
+            let mut place = self.head_place();
+            for i in 0..index {
+                let node = match *place.as_ref() {
+                    Some(node) => node,
+                    None => panic!(
+                        "index out of bounds: the len is {} but the index is {}",
+                        i, index,
+                    ),
+                };
+                place = NonNull::new_unchecked(node.as_ref().next.get());
+            }
+            place
+        }
+    }
 
-        let buf = Rc::new(RefCell::new(vec![]));
-        let get_last_dropped = || replace(&mut *buf.borrow_mut(), vec![]);
-        let new_s = |value| S { value, buf: Rc::clone(&buf) };
-        let get_values = |v: &[Handle<S>]| -> Vec<i32> {
-            v.iter().map(|h| h.value).collect::<Vec<i32>>()
-        };
+    /// Re-link the values of `handles` into the list in the given order,
+    /// replacing the current content.
+    ///
+    /// All values currently in the list must be included.
+    fn relink_all(&self, handles: &[Handle<T>]) {
+        handles.iter().for_each(|h| Handle::detach(h));
+        debug_assert!(self.is_empty());
+        unsafe { self.link_all_at(self.head_place(), handles); }
+    }
 
-        let h5;
-        {
-            let ls = WeakList::new();
-            let get_snapshot = || get_values(&ls.upgrade_all());
-            let mut handles = vec![];
+    /// Move the values of `handles` to `place` in the given order.
+    ///
+    /// `place` must be in a linked node of this list not in `handles`, or the
+    /// list head.
+    unsafe fn link_all_at(&self, mut place: NonNull<NodePtr<T>>, handles: &[Handle<T>]) {
+        let mut prev = None;
+        for h in handles {
+            if prev == Some(h.cur) { // Already there.
+                continue;
+            }
+            Handle::detach(h);
+            Node::link_at(h.cur, place, self.head_place());
+            place = NonNull::new_unchecked(h.cur.as_ref().next.get());
+            prev = Some(h.cur);
+        }
+    }
 
-            handles.push(ls.new_elem(new_s(1)));
-            handles.push(ls.new_elem(new_s(2)));
-            handles.push(ls.new_elem(new_s(3)));
-            assert_eq!(get_values(&handles), [1, 2, 3]);
-            assert_eq!(get_snapshot(), [3, 2, 1]);
-            assert_eq!(get_last_dropped(), []);
+    /// Pre-allocate spaces for `n` more values, so that the next `n` calls of
+    /// `new_elem` will not allocate.
+    ///
+    /// Note that the space of a value is always freed to the allocator when it
+    /// dies, instead of going back to the pool.
+    pub fn reserve_pool(&self, n: usize) {
+        unsafe {
+            let pool = &mut *self.pool.get();
+            pool.reserve(n);
+            pool.extend((0..n).map(|_| Box::new(MaybeUninit::uninit())));
+        }
+    }
 
-            handles.pop();
-            assert_eq!(get_values(&handles), [1, 2]);
-            assert_eq!(get_snapshot(), [2, 1]);
-            assert_eq!(get_last_dropped(), [3]);
+    /// Free all pre-allocated spaces in the pool.
+    ///
+    /// Values in the list are not affected.
+    pub fn shrink_to_fit(&self) {
+        unsafe { *self.pool.get() = Vec::new(); }
+    }
+
+    /// Free pre-allocated spaces in the pool until at most `n` are kept.
+    ///
+    /// Values in the list are not affected.
+    pub fn shrink_pool_to(&self, n: usize) {
+        unsafe {
+            let pool = &mut *self.pool.get();
+            pool.truncate(n);
+            pool.shrink_to_fit();
+        }
+    }
+
+    /// The number of pre-allocated spaces for values.
+    pub fn pool_capacity(&self) -> usize {
+        unsafe { (*self.pool.get()).len() }
+    }
+
+    /// Report the memory usage of the list.
+    ///
+    /// Like `len`, it walks the whole list in O(n).
+    pub fn capacity_report(&self) -> CapacityReport {
+        CapacityReport {
+            live: self.len(),
+            pooled: self.pool_capacity(),
+            node_size: ::std::mem::size_of::<Node<T>>(),
+        }
+    }
+
+    /// Count the weak references in the list.
+    ///
+    /// The length is not tracked, so it walks the whole list in O(n).
+    pub fn len(&self) -> usize {
+        unsafe {
+            let mut n = 0;
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                n += 1;
+                cur = *cur_node.as_ref().next.get();
+            }
+            n
+        }
+    }
+
+    /// Count values in the list which are still alive.
+    ///
+    /// It always equals to `len`, since a weak reference is removed from the
+    /// list immediately when its value dies.
+    pub fn live_len(&self) -> usize {
+        self.len()
+    }
+
+    /// Remove weak references of dead values from the list, and return how
+    /// many are removed.
+    ///
+    /// A weak reference is always removed immediately when its value dies,
+    /// so this only walks the list in O(n) to check that, and returns 0.
+    /// It is kept for a possible lazy removal mode in the future.
+    pub fn sweep(&self) -> usize {
+        unsafe {
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                assert!(cur_node.as_ref().strong_count.get() >= 1, "dead value in the list");
+                cur = *cur_node.as_ref().next.get();
+            }
+        }
+        0
+    }
+
+    /// Sum up the numbers of `Handle`s to each value in the list.
+    ///
+    /// Like `len`, it walks the whole list in O(n).
+    pub fn total_strong(&self) -> usize {
+        unsafe {
+            let mut n = 0;
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                n += cur_node.as_ref().strong_count.get();
+                cur = *cur_node.as_ref().next.get();
+            }
+            n
+        }
+    }
+
+    /// Check if there is no weak reference in the list.
+    pub fn is_empty(&self) -> bool {
+        unsafe { (*self.head().get()).is_none() }
+    }
+
+    /// Upgrade the first value in the list, if any.
+    pub fn first(&self) -> Option<Handle<T>> {
+        unsafe { (*self.head().get()).map(|node| Handle::from_raw_node(node)) }
+    }
+
+    /// Remove the first value from the list, and return a `Handle` to it.
+    pub fn pop_front(&self) -> Option<Handle<T>> {
+        let handle = self.first()?;
+        Handle::detach(&handle);
+        Some(handle)
+    }
+
+    /// Remove the last value from the list, and return a `Handle` to it.
+    ///
+    /// The tail is not tracked, so it walks the whole list in O(n).
+    pub fn pop_back(&self) -> Option<Handle<T>> {
+        self.drain_rev().next()
+    }
+
+    /// Clear the list and free spaces for all weak references.
+    ///
+    /// Note that it never cause the drop of any value.
+    /// All values existing in the `WeakList` must still be strongly
+    /// referenced by some `Handle`s outside.
+    pub fn clear(&self) {
+        unsafe {
+            let mut cur = (*self.head().get()).take();
+            while let Some(cur_node) = cur {
+                let node = cur_node.as_ref();
+                cur = (*node.next.get()).take();
+                node.prev_next.set(None);
+                node.list.set(None);
+            }
+        }
+    }
+
+    /// Clear the list if `cond` returns true for it.
+    ///
+    /// Return whether the list is cleared.
+    pub fn clear_if<F: FnOnce(&WeakList<T>) -> bool>(&self, cond: F) -> bool {
+        if cond(self) {
+            self.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a snapshot for all weak-referenced values in the `WeakList`
+    /// and upgrade them.
+    ///
+    /// It will not change the list.
+    ///
+    /// Values in the list are not contiguous, so there is no slice view of
+    /// them. Borrowing the list does not keep them alive either, since any
+    /// outside `Handle` may be dropped meanwhile. The snapshot is the closest
+    /// alternative to such a view.
+    pub fn upgrade_all(&self) -> Vec<Handle<T>> {
+        let mut v = Vec::with_capacity(self.len());
+        self.push_all_to(&mut v);
+        v
+    }
+
+    /// The same as `upgrade_all`, but stores `Handle`s into `out` to reuse its
+    /// allocation.
+    ///
+    /// `out` is cleared first.
+    pub fn upgrade_all_into(&self, out: &mut Vec<Handle<T>>) {
+        out.clear();
+        out.reserve(self.len());
+        self.push_all_to(out);
+    }
+
+    /// The same as `upgrade_all`.
+    pub fn clone_handles(&self) -> Vec<Handle<T>> {
+        self.upgrade_all()
+    }
+
+    /// The same as `upgrade_all`, but appends `Handle`s to `out`.
+    ///
+    /// Unlike `upgrade_all_into`, `out` is not cleared.
+    pub fn clone_handles_into(&self, out: &mut Vec<Handle<T>>) {
+        out.reserve(self.len());
+        self.push_all_to(out);
+    }
+
+    fn push_all_to(&self, v: &mut Vec<Handle<T>>) {
+        unsafe {
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                // Dead values are always removed immediately.
+                debug_assert!(cur_node.as_ref().strong_count.get() >= 1);
+                v.push(Handle::from_raw_node(cur_node));
+                cur = *cur_node.as_ref().next.get();
+            }
+        }
+    }
+
+    /// The same as `upgrade_all`, except it clears the list before return.
+    pub fn take_all(&self) -> Vec<Handle<T>> {
+        let v = self.upgrade_all();
+        v.iter().for_each(|h| Handle::detach(h));
+        v
+    }
+
+    /// The same as `upgrade_all`, but collects the `Handle`s into a
+    /// `LinkedList`.
+    pub fn to_linked_list(&self) -> LinkedList<Handle<T>> {
+        self.iter().collect()
+    }
+
+    /// Iterate over all weak-referenced values in the `WeakList`,
+    /// upgrading each of them when reached.
+    ///
+    /// Unlike `upgrade_all`, nothing is collected in advance, so breaking
+    /// early only upgrades the values reached.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            next: self.first(),
+        }
+    }
+
+    /// The same as `iter`, but also yields the number of `Handle`s currently
+    /// referencing each value, not counting the yielded one.
+    ///
+    /// It is useful for finding out which values are still held by others.
+    pub fn iter_with_counts(&self) -> impl Iterator<Item = (Handle<T>, usize)> + '_ {
+        self.iter().map(|h| {
+            let count = unsafe { h.cur.as_ref().strong_count.get() };
+            (h, count - 1)
+        })
+    }
+
+    /// Find the index of the first value satisfying `f`.
+    ///
+    /// The index is the current position in the list, which shifts when
+    /// values before it are removed.
+    pub fn position<F: FnMut(&T) -> bool>(&self, mut f: F) -> Option<usize> {
+        self.iter().position(|h| f(&h))
+    }
+
+    /// Rotate the list in-place such that the last `n` values move to the
+    /// front, keeping their order.
+    ///
+    /// `n` is taken modulo the length of the list.
+    pub fn rotate_right(&self, n: usize) {
+        let mut v = self.upgrade_all();
+        if !v.is_empty() {
+            let k = n % v.len();
+            v.rotate_right(k);
+            self.relink_all(&v);
+        }
+    }
+
+    /// Retain only the values satisfying `f`, and remove weak references of
+    /// others from the list.
+    ///
+    /// Like `clear`, it never cause the drop of any value.
+    /// It is safe for `f` to modify the list or drop `Handle`s. The pass
+    /// goes on from the last kept value, so values detached or moved to
+    /// another list by `f` are skipped, and values added by `f` after it are
+    /// visited too.
+    ///
+    /// Returns the number of removed values, not counting ones removed by
+    /// `f` itself.
+    pub fn retain<F: FnMut(&T) -> bool>(&self, mut f: F) -> usize {
+        self.retain_with_index(|_, x| f(x))
+    }
+
+    /// The same as `retain`, but also passes the index of each value to `f`.
+    ///
+    /// The index is counted before any removal in this pass.
+    pub fn retain_with_index<F: FnMut(usize, &T) -> bool>(&self, mut f: F) -> usize {
+        let mut walk = RetainWalk::new(self);
+        let (mut i, mut removed) = (0, 0);
+        while let Some((_, detached)) = walk.step(self, |h| f(i, h)) {
+            i += 1;
+            if detached {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// The same as `retain`, but passes a `Handle` to each value to `f`,
+    /// which can be kept by `f`.
+    pub fn retain_handles<F: FnMut(Handle<T>) -> bool>(&self, mut f: F) -> usize {
+        let mut walk = RetainWalk::new(self);
+        let mut removed = 0;
+        while let Some((_, detached)) = walk.step(self, |h| f(Handle::clone(h))) {
+            if detached {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Iterate over clones of all values in the list.
+    pub fn iter_values(&self) -> impl Iterator<Item = T> + '_
+    where
+        T: Clone,
+    {
+        self.iter().map(|h| T::clone(&h))
+    }
+
+    /// Swap two values in the list by their indices.
+    ///
+    /// # Panics
+    /// Panics if `a` or `b` is out of bounds.
+    pub fn swap(&self, a: usize, b: usize) {
+        let v = self.upgrade_all();
+        let len = v.len();
+        assert!(
+            a < len && b < len,
+            "index out of bounds: the len is {} but the indices are {} and {}",
+            len, a, b,
+        );
+        let (i, j) = if a < b { (a, b) } else { (b, a) };
+        if i == j {
+            return;
+        }
+        unsafe {
+            // Move `v[j]` right before `v[i]`.
+            let place = v[i].cur.as_ref().prev_next.get().unwrap();
+            Handle::detach(&v[j]);
+            Node::link_at(v[j].cur, place, self.head_place());
+            // Move `v[i]` to where `v[j]` was, unless they were adjacent.
+            if j != i + 1 {
+                let place = NonNull::new_unchecked(v[j - 1].cur.as_ref().next.get());
+                Handle::detach(&v[i]);
+                Node::link_at(v[i].cur, place, self.head_place());
+            }
+        }
+    }
+
+    /// Lazily remove the weak references of values satisfying `pred` from
+    /// the list, and yield `Handle`s to them.
+    ///
+    /// Values not yet reached are left untouched if the iterator is dropped.
+    /// Like `retain`, it is safe for `pred` to modify the list, and values
+    /// moved to another list by `pred` are neither removed nor yielded.
+    pub fn extract_if<'a, F>(&'a self, mut pred: F) -> impl Iterator<Item = Handle<T>> + 'a
+    where
+        F: FnMut(&T) -> bool + 'a,
+    {
+        let mut walk = RetainWalk::new(self);
+        ::std::iter::from_fn(move || loop {
+            if let (h, true) = walk.step(self, |h| !pred(h))? {
+                return Some(h);
+            }
+        })
+    }
+
+    /// Build a new list of values mapped from values in this list by `f`,
+    /// keeping the order.
+    ///
+    /// `Handle`s to the new values are returned along with the new list,
+    /// which are the only strong references to them.
+    /// The two lists are independent of each other.
+    pub fn map_collect<U, F: FnMut(&T) -> U>(&self, mut f: F) -> (WeakList<U>, Vec<Handle<U>>) {
+        let values = self.iter().map(|h| f(&h)).collect::<Vec<U>>();
+        let list = WeakList::new();
+        let mut handles = values.into_iter().rev()
+            .map(|x| list.new_elem(x))
+            .collect::<Vec<_>>();
+        handles.reverse();
+        (list, handles)
+    }
+
+    /// Split the list into two at `index`. The first one contains values in
+    /// `[0, index)`, and the second one contains the rest.
+    ///
+    /// The second list has the same insertion order and maximum length as
+    /// this one, but starts with an empty pool.
+    ///
+    /// # Panics
+    /// Panics if `index > len`.
+    pub fn split_at(self, index: usize) -> (WeakList<T>, WeakList<T>) {
+        let mut rest = WeakList::new();
+        rest.order = self.order;
+        rest.max_len = self.max_len;
+        unsafe {
+            let place = self.place_at(index);
+            if let Some(node) = (*place.as_ptr()).take() {
+                node.as_ref().prev_next.set(Some(rest.head_place()));
+                *rest.head().get() = Some(node);
+            }
+            let mut cur = *rest.head().get();
+            while let Some(cur_node) = cur {
+                cur_node.as_ref().list.set(Some(rest.head_place()));
+                cur = *cur_node.as_ref().next.get();
+            }
+        }
+        (self, rest)
+    }
+
+    /// Find the value with the given id from `Handle::id` in the list, and
+    /// upgrade it.
+    ///
+    /// It walks the list in O(n).
+    pub fn upgrade_by_id(&self, id: usize) -> Option<Handle<T>> {
+        unsafe {
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                if cur_node.as_ptr() as usize == id {
+                    return Some(Handle::from_raw_node(cur_node));
+                }
+                cur = *cur_node.as_ref().next.get();
+            }
+            None
+        }
+    }
+
+    /// Lazily upgrade values in the list in chunks of `size`.
+    /// The last chunk may be shorter.
+    ///
+    /// # Panics
+    /// Panics if `size` is 0.
+    pub fn chunks(&self, size: usize) -> impl Iterator<Item = Vec<Handle<T>>> + '_ {
+        assert!(size != 0, "chunk size must be non-zero");
+        let mut it = self.iter();
+        ::std::iter::from_fn(move || {
+            let chunk = it.by_ref().take(size).collect::<Vec<_>>();
+            if chunk.is_empty() { None } else { Some(chunk) }
+        })
+    }
+
+    /// Upgrade values which are in both this list and `other`.
+    ///
+    /// A value can be in only one list, so values are compared by equality.
+    /// Equal values are deduplicated, keeping the first one in this list.
+    /// It takes O(n * m) comparisons.
+    pub fn intersection(&self, other: &WeakList<T>) -> Vec<Handle<T>>
+    where
+        T: PartialEq,
+    {
+        let others = other.upgrade_all();
+        dedup_handles(self.iter().filter(|h| {
+            others.iter().any(|o| Handle::same_or_equal(h, o))
+        }))
+    }
+
+    /// Upgrade values which are in either this list or `other`.
+    ///
+    /// A value can be in only one list, so values are compared by equality.
+    /// Equal values are deduplicated, keeping the first one in this list
+    /// followed by `other`.
+    /// It takes O((n + m) ^ 2) comparisons.
+    pub fn union(&self, other: &WeakList<T>) -> Vec<Handle<T>>
+    where
+        T: PartialEq,
+    {
+        dedup_handles(self.iter().chain(other.iter()))
+    }
+
+    /// Push the weak references of existing values into the front of the list,
+    /// keeping their order.
+    ///
+    /// Like `attach`, values are detached from their current lists first.
+    /// Note that `handles` are dropped when it returns, so the values must be
+    /// held by other `Handle`s to stay in the list.
+    pub fn extend_from_handles<I: IntoIterator<Item = Handle<T>>>(&self, handles: I) {
+        // Collect first, so no user code runs during linking.
+        let handles = handles.into_iter().collect::<Vec<_>>();
+        unsafe { self.link_all_at(self.head_place(), &handles); }
+    }
+
+    /// Fold values in the list from the first to the last with `f`, using a
+    /// clone of the first value as the initial accumulator.
+    ///
+    /// Return `None` if the list is empty.
+    pub fn reduce<F: FnMut(T, &T) -> T>(&self, mut f: F) -> Option<T>
+    where
+        T: Clone,
+    {
+        let mut it = self.iter();
+        let first = T::clone(&*it.next()?);
+        Some(it.fold(first, |acc, h| f(acc, &h)))
+    }
+
+    /// Lazily upgrade all overlapping windows of `n` consecutive values in the
+    /// list. Nothing is yielded if the list is shorter than `n`.
+    ///
+    /// Each window holds its own `n` `Handle`s.
+    ///
+    /// # Panics
+    /// Panics if `n` is 0.
+    pub fn windows(&self, n: usize) -> impl Iterator<Item = Vec<Handle<T>>> + '_ {
+        assert!(n != 0, "window size must be non-zero");
+        let mut it = self.iter();
+        let mut window = Vec::with_capacity(n);
+        ::std::iter::from_fn(move || {
+            if window.len() == n {
+                window.remove(0);
+            }
+            while window.len() < n {
+                window.push(it.next()?);
+            }
+            Some(window.clone())
+        })
+    }
+
+    /// Call `f` on values in the list in order, until it returns
+    /// `ControlFlow::Break`.
+    pub fn for_each_while<F: FnMut(&T) -> ControlFlow<()>>(&self, mut f: F) {
+        for h in self.iter() {
+            if f(&h).is_break() {
+                break;
+            }
+        }
+    }
+
+    /// Upgrade leading values in the list satisfying `f`, stopping at the
+    /// first value which does not.
+    pub fn take_while<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<Handle<T>> {
+        self.iter().take_while(|h| f(h)).collect()
+    }
+
+    /// Upgrade values in the list after leading values satisfying `f`.
+    pub fn skip_while<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<Handle<T>> {
+        self.iter().skip_while(|h| f(h)).collect()
+    }
+
+    /// Render the structure of the list in forward order for snapshot tests.
+    ///
+    /// Values are numbered by position as `#0`, `#1`, ... instead of showing
+    /// their addresses, so the output is the same across runs. The strong
+    /// count of each value does not include the dump itself.
+    #[cfg(debug_assertions)]
+    pub fn debug_dump(&self) -> String
+    where
+        T: ::std::fmt::Debug,
+    {
+        use std::fmt::Write;
+
+        let handles = self.upgrade_all();
+        let id = |i: Option<usize>| i.map_or("none".to_owned(), |i| format!("#{}", i));
+        let mut out = String::new();
+        writeln!(out, "head: {}", id(if handles.is_empty() { None } else { Some(0) })).unwrap();
+        for (i, h) in handles.iter().enumerate() {
+            let strong = Handle::strong_count(h) - 1;
+            writeln!(out, "{}: {:?} (strong: {})", id(Some(i)), &**h, strong).unwrap();
+        }
+        writeln!(out, "tail: {}", id(handles.len().checked_sub(1))).unwrap();
+        out
+    }
+
+    /// The same as `retain`, but also removes `Handle`s to the removed
+    /// values from `owners`, keeping the order of others.
+    ///
+    /// `Handle`s to values not in the list are kept in `owners`. Values may be
+    /// dropped when their `Handle`s are removed from `owners`.
+    pub fn retain_owned<F: FnMut(&T) -> bool>(
+        &self,
+        owners: &mut Vec<Handle<T>>,
+        mut f: F,
+    ) -> usize {
+        use std::collections::HashSet;
+
+        // Keep removed values alive, so their ids are not reused until done.
+        let mut walk = RetainWalk::new(self);
+        let mut removed = vec![];
+        while let Some((h, detached)) = walk.step(self, |h| f(h)) {
+            if detached {
+                removed.push(h);
+            }
+        }
+        let ids: HashSet<_> = removed.iter().map(Handle::id).collect();
+        owners.retain(|h| !ids.contains(&Handle::id(h)));
+        removed.len()
+    }
+
+    /// Iterate over the values from the back to the front, in O(n) time and
+    /// space.
+    ///
+    /// Like `iter`, it yields `Handle`s since the values may be dropped
+    /// anytime. The list has no tail pointer, so all values are upgraded
+    /// first, and the iterator is not affected by later changes to the list.
+    pub fn iter_rev(&self) -> impl Iterator<Item = Handle<T>> {
+        self.upgrade_all().into_iter().rev()
+    }
+
+    /// Move all values in `other` into `self`, starting at `index` and
+    /// keeping their order. `other` will be empty.
+    ///
+    /// It does nothing if `other` is `self`.
+    ///
+    /// # Panics
+    /// Panics if `index > len`.
+    pub fn splice(&self, index: usize, other: &WeakList<T>) {
+        let place = self.place_at(index);
+        if ::std::ptr::eq(self, other) {
+            return;
+        }
+        let handles = other.take_all();
+        unsafe { self.link_all_at(place, &handles); }
+    }
+
+    /// Push each value to the front of the list in turn, so the last value
+    /// becomes the first in the list. Returns `Handle`s to them in the input
+    /// order.
+    ///
+    /// Unlike `new_elem`, it ignores the insertion order of the list. For a
+    /// list created by `with_max_len`, the oldest values are evicted first to
+    /// make room, and if more than `n` values are pushed, the extra ones are
+    /// evicted from the same end as the oldest.
+    pub fn extend_front<I: IntoIterator<Item = T>>(&self, iter: I) -> Vec<Handle<T>> {
+        let handles: Vec<_> = iter.into_iter()
+            .map(|value| unsafe { Handle::from_raw_node(self.new_node(value)) })
+            .collect();
+        self.evict_for(handles.len());
+        for h in &handles {
+            unsafe { Node::link_at(h.cur, self.head_place(), self.head_place()); }
+        }
+        self.evict_for(0);
+        handles
+    }
+
+    /// Push each value to the back of the list in turn, keeping their order.
+    /// Returns `Handle`s to them in the input order.
+    ///
+    /// The last value is found only once, so it takes O(n + m) time for `m`
+    /// values. Like `extend_front`, it ignores the insertion order of the
+    /// list, and evicts the oldest values first to make room.
+    pub fn extend_back<I: IntoIterator<Item = T>>(&self, iter: I) -> Vec<Handle<T>> {
+        let handles: Vec<_> = iter.into_iter()
+            .map(|value| unsafe { Handle::from_raw_node(self.new_node(value)) })
+            .collect();
+        self.evict_for(handles.len());
+        unsafe { self.link_all_at(self.end_place(), &handles); }
+        self.evict_for(0);
+        handles
+    }
+
+    /// The same as `retain`, but stops at the first value for which `f`
+    /// returns `Break`, keeping it and all values after it.
+    pub fn retain_try<F: FnMut(&T) -> ControlFlow<(), bool>>(&self, mut f: F) -> usize {
+        let mut walk = RetainWalk::new(self);
+        let (mut removed, mut stopped) = (0, false);
+        while !stopped {
+            let step = walk.step(self, |h| match f(h) {
+                ControlFlow::Break(()) => {
+                    stopped = true;
+                    true
+                }
+                ControlFlow::Continue(keep) => keep,
+            });
+            match step {
+                Some((_, true)) => removed += 1,
+                Some((_, false)) => {}
+                None => break,
+            }
+        }
+        removed
+    }
+
+    /// Check if the values in the list are equal to `expected` in order.
+    ///
+    /// It upgrades values one by one like `iter`, without collecting them.
+    pub fn values_eq(&self, expected: &[T]) -> bool
+    where
+        T: PartialEq,
+    {
+        let mut it = self.iter();
+        for x in expected {
+            match it.next() {
+                Some(h) if *h == *x => {}
+                _ => return false,
+            }
+        }
+        it.next().is_none()
+    }
+
+    /// Find the first value satisfying `f`, from the front.
+    pub fn first_where<F: FnMut(&T) -> bool>(&self, mut f: F) -> Option<Handle<T>> {
+        self.iter().find(|h| f(h))
+    }
+
+    /// Find the last value satisfying `f`.
+    ///
+    /// The list can only be walked forward, so `f` is called on every value
+    /// from the front.
+    pub fn last_where<F: FnMut(&T) -> bool>(&self, mut f: F) -> Option<Handle<T>> {
+        self.iter().filter(|h| f(h)).last()
+    }
+
+    /// Check if two lists contain equal values with the same multiplicity,
+    /// ignoring the order.
+    ///
+    /// Values are compared by `T: Eq + Hash` rather than by identity. Equal
+    /// values are counted, and each must appear as many times in `other` as
+    /// in `self`, so `[1, 1, 2]` equals `[2, 1, 1]` but not `[1, 2, 2]`.
+    ///
+    /// It upgrades all values of both lists, and takes O(n) expected time.
+    pub fn eq_as_set(&self, other: &WeakList<T>) -> bool
+    where
+        T: Eq + ::std::hash::Hash,
+    {
+        use std::collections::HashMap;
+
+        let (a, b) = (self.upgrade_all(), other.upgrade_all());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut counts = HashMap::new();
+        for h in &a {
+            *counts.entry(&**h).or_insert(0usize) += 1;
+        }
+        for h in &b {
+            match counts.get_mut(&**h) {
+                Some(n) if *n > 0 => *n -= 1,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Exchange the values of two lists in O(1) time.
+    ///
+    /// Pools and other settings of the lists are kept.
+    pub fn swap_with(&self, other: &WeakList<T>) {
+        // The first nodes point to the heads, which are swapped along with them.
+        self.head.swap(&other.head);
+    }
+
+    /// Count the values satisfying `f`.
+    ///
+    /// Like `iter`, each value is upgraded while `f` is called on it, since
+    /// `f` may drop `Handle`s. Nothing is allocated.
+    pub fn count_where<F: FnMut(&T) -> bool>(&self, mut f: F) -> usize {
+        self.iter().filter(|h| f(h)).count()
+    }
+
+    /// Remove values from the back of the list one by one, yielding
+    /// `Handle`s to them.
+    ///
+    /// Values are removed only when reached, so dropping the iterator early
+    /// keeps the rest in the list. The list has no tail pointer, so each
+    /// step walks the list in O(n) time.
+    pub fn drain_rev(&self) -> impl Iterator<Item = Handle<T>> + '_ {
+        ::std::iter::from_fn(move || unsafe {
+            let last = self.last_node()?;
+            let handle = Handle::from_raw_node(last);
+            Handle::detach(&handle);
+            Some(handle)
+        })
+    }
+
+    /// Upgrade the values satisfying `f` in order.
+    ///
+    /// Like `count_where`, every visited value is upgraded while `f` is called
+    /// on it, since `f` may drop `Handle`s. Only `Handle`s to matching ones are
+    /// kept, so unlike `upgrade_all`, no `Handle` to other values is collected.
+    pub fn handles_matching<F: FnMut(&T) -> bool>(&self, mut f: F) -> Vec<Handle<T>> {
+        self.iter().filter(|h| f(h)).collect()
+    }
+
+    /// Retain only the values from the first one satisfying `start`, to the
+    /// first one satisfying `end` after it, both inclusive. Returns the
+    /// number of removed values like `retain`.
+    ///
+    /// `end` is first checked on the value satisfying `start`. If `start`
+    /// never matches, all values are removed. If `end` never matches, values
+    /// are kept to the end of the list.
+    pub fn retain_range<F, G>(&self, mut start: F, mut end: G) -> usize
+    where
+        F: FnMut(&T) -> bool,
+        G: FnMut(&T) -> bool,
+    {
+        // 0: before the range, 1: in the range, 2: after the range.
+        let mut state = 0;
+        self.retain(|x| {
+            if state == 0 && start(x) {
+                state = 1;
+            }
+            match state {
+                0 => false,
+                1 => {
+                    if end(x) {
+                        state = 2;
+                    }
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Create a `Weak` to each value in the list in order, without upgrading
+    /// them.
+    ///
+    /// The `Weak`s do not keep values alive, but keep their spaces until
+    /// dropped, even after the list is dropped.
+    pub fn weak_all(&self) -> Vec<Weak<T>> {
+        let mut v = Vec::new();
+        unsafe {
+            let mut cur = *self.head().get();
+            while let Some(cur_node) = cur {
+                Node::inc_weak(cur_node);
+                v.push(Weak { cur: cur_node });
+                cur = *cur_node.as_ref().next.get();
+            }
+        }
+        v
+    }
+}
+
+fn dedup_handles<T: PartialEq, I: Iterator<Item = Handle<T>>>(iter: I) -> Vec<Handle<T>> {
+    let mut v: Vec<Handle<T>> = Vec::new();
+    for h in iter {
+        if !v.iter().any(|x| Handle::same_or_equal(x, &h)) {
+            v.push(h);
+        }
+    }
+    v
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = Handle<T>;
+
+    fn next(&mut self) -> Option<Handle<T>> {
+        let cur = self.next.take()?;
+        if !self.list.contains_node(&cur) {
+            return None;
+        }
+        unsafe {
+            let next = *cur.cur.as_ref().next.get();
+            self.next = next.map(|node| Handle::from_raw_node(node));
+        }
+        Some(cur)
+    }
+}
+
+impl<'a, T> Clone for Iter<'a, T> {
+    fn clone(&self) -> Self {
+        Iter {
+            list: self.list,
+            next: self.next.clone(),
+        }
+    }
+}
+
+/// A pass of `retain` over a list, which can be stopped between values.
+///
+/// It holds the last kept value and continues from its live `next`, so the
+/// predicate can detach or move any value, including the next one.
+struct RetainWalk<T> {
+    kept: Option<Handle<T>>,
+    next: Option<Handle<T>>,
+}
+
+impl<T> RetainWalk<T> {
+    fn new(list: &WeakList<T>) -> Self {
+        RetainWalk { kept: None, next: list.first() }
+    }
+
+    /// Call `f` on the next value of `list`, and detach the value if `f`
+    /// returns `false`. Returns the value and whether it is detached.
+    fn step<F>(&mut self, list: &WeakList<T>, f: F) -> Option<(Handle<T>, bool)>
+    where
+        F: FnOnce(&Handle<T>) -> bool,
+    {
+        let next_of = |h: &Handle<T>| unsafe {
+            (*h.cur.as_ref().next.get()).map(|node| Handle::from_raw_node(node))
+        };
+        let h = self.next.take()?;
+        // Only used if `f` removes both `h` and `kept`.
+        let next = next_of(&h);
+        let keep = f(&h);
+        let detached = !keep && list.contains_node(&h);
+        if detached {
+            Handle::detach(&h);
+        } else if list.contains_node(&h) {
+            self.kept = Some(Handle::clone(&h));
+        }
+        self.next = match self.kept {
+            Some(ref k) if list.contains_node(k) => next_of(k),
+            Some(_) => next.filter(|h| list.contains_node(h)),
+            None => list.first(),
+        };
+        Some((h, detached))
+    }
+}
+
+impl<T> Drop for WeakList<T> {
+    fn drop(&mut self) {
+        // Values may outlive the list, so they must not point to it anymore.
+        self.clear();
+        unsafe { drop(Box::from_raw(self.head.get().as_ptr())); }
+    }
+}
+
+impl<T> Default for WeakList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, U: ?Sized> HandleMap<T, U> {
+    /// Get the `Handle` to the whole value.
+    pub fn handle(this: &Self) -> &Handle<T> {
+        &this.handle
+    }
+
+    /// Discard the projection and return the `Handle` to the whole value.
+    pub fn into_handle(this: Self) -> Handle<T> {
+        this.handle
+    }
+}
+
+impl<T, U: ?Sized> Clone for HandleMap<T, U> {
+    fn clone(&self) -> Self {
+        HandleMap { handle: self.handle.clone(), ptr: self.ptr }
+    }
+}
+
+impl<T, U: ?Sized> Deref for HandleMap<T, U> {
+    type Target = U;
+
+    #[inline]
+    fn deref(&self) -> &U {
+        // The value is kept alive and never mutated while `handle` exists,
+        // since `get_mut` requires a unique `&mut Handle`.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> ListBuilder<T> {
+    /// Push a value to the back, and return a reference to its `Handle`.
+    pub fn push(&mut self, value: T) -> &Handle<T> {
+        let handle = self.list.new_elem(value);
+        self.handles.push(handle);
+        self.handles.last().unwrap()
+    }
+
+    /// Finish building, returning the list and `Handle`s to all values in
+    /// it, both in the pushed order.
+    pub fn finish(self) -> (WeakList<T>, Vec<Handle<T>>) {
+        self.list.relink_all(&self.handles);
+        (self.list, self.handles)
+    }
+}
+
+// All links and counts are updated without calling user code in between, so
+// they are never left half-updated by a panic. The value is only mutated
+// through a unique `Handle`, like `Rc`, so only shared references of it need
+// to be unwind-safe.
+impl<T: RefUnwindSafe> UnwindSafe for Handle<T> {}
+impl<T: RefUnwindSafe> RefUnwindSafe for Handle<T> {}
+impl<T: RefUnwindSafe> UnwindSafe for Weak<T> {}
+impl<T: RefUnwindSafe> RefUnwindSafe for Weak<T> {}
+impl<T: RefUnwindSafe> UnwindSafe for WeakList<T> {}
+impl<T: RefUnwindSafe> RefUnwindSafe for WeakList<T> {}
+
+#[cfg(test)]
+#[allow(clippy::mem_replace_with_default)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    struct S {
+        value: i32,
+        buf: Rc<RefCell<Vec<i32>>>,
+    }
+
+    impl Drop for S {
+        fn drop(&mut self) {
+            self.buf.borrow_mut().push(self.value);
+        }
+    }
+
+    fn values<T: Copy>(ls: &WeakList<T>) -> Vec<T> {
+        ls.iter().map(|h| *h).collect()
+    }
+
+    /// Check the back pointers of all nodes.
+    fn assert_consistent<T>(ls: &WeakList<T>) {
+        unsafe {
+            let mut place = ls.head_place();
+            while let Some(node) = *place.as_ref() {
+                let node = node.as_ref();
+                assert_eq!(node.prev_next.get(), Some(place));
+                assert_eq!(node.list.get(), Some(ls.head_place()));
+                assert!(node.strong_count.get() >= 1);
+                place = NonNull::new_unchecked(node.next.get());
+            }
+        }
+    }
+
+    #[test]
+    fn basic_test() {
+        use std::mem::replace;
+
+        let buf = Rc::new(RefCell::new(vec![]));
+        let get_last_dropped = || replace(&mut *buf.borrow_mut(), vec![]);
+        let new_s = |value| S { value, buf: Rc::clone(&buf) };
+        let get_values = |v: &[Handle<S>]| -> Vec<i32> {
+            v.iter().map(|h| h.value).collect::<Vec<i32>>()
+        };
+
+        let h5;
+        {
+            let ls = WeakList::new();
+            let get_snapshot = || get_values(&ls.upgrade_all());
+            let mut handles = vec![];
+
+            handles.push(ls.new_elem(new_s(1)));
+            handles.push(ls.new_elem(new_s(2)));
+            handles.push(ls.new_elem(new_s(3)));
+            assert_eq!(get_values(&handles), [1, 2, 3]);
+            assert_eq!(get_snapshot(), [3, 2, 1]);
+            assert_eq!(get_last_dropped(), []);
+
+            handles.pop();
+            assert_eq!(get_values(&handles), [1, 2]);
+            assert_eq!(get_snapshot(), [2, 1]);
+            assert_eq!(get_last_dropped(), [3]);
 
             ls.new_elem(new_s(4)); // Immediately drop handle.
             assert_eq!(get_snapshot(), [2, 1]);
             assert_eq!(get_last_dropped(), [4]);
 
-            Handle::detach(&handles[0]); // Remove `1` from list.
-            assert_eq!(get_values(&handles), [1, 2]); // Handles will not change.
-            assert_eq!(get_snapshot(), [2]);
-            assert_eq!(get_last_dropped(), []); // No drop now.
+            Handle::detach(&handles[0]); // Remove `1` from list.
+            assert_eq!(get_values(&handles), [1, 2]); // Handles will not change.
+            assert_eq!(get_snapshot(), [2]);
+            assert_eq!(get_last_dropped(), []); // No drop now.
+
+            handles.remove(0); // Drop handle to `1`.
+            assert_eq!(get_values(&handles), [2]);
+            assert_eq!(get_snapshot(), [2]);
+            assert_eq!(get_last_dropped(), [1]);
+
+            ls.clear();
+            assert_eq!(get_snapshot(), []);
+            assert_eq!(get_last_dropped(), []); // `clear` never cause drop.
+
+            handles.clear();
+            assert_eq!(get_last_dropped(), [2]);
+
+            handles.push(ls.new_elem(new_s(5)));
+            handles = ls.take_all();
+            assert_eq!(get_values(&handles), [5]);
+            assert_eq!(get_snapshot(), []); // `take_all` remove all elems from list.
+            assert_eq!(get_last_dropped(), []);
+
+            h5 = Handle::clone(&handles[0]);
+        }
+        assert_eq!(h5.value, 5); // Handle can outlive the list
+        assert_eq!(get_last_dropped(), []);
+
+        drop(h5);
+        assert_eq!(get_last_dropped(), [5]);
+    }
+
+    #[test]
+    fn drop_list_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem(1);
+        let b = ls.new_elem(2);
+        drop(ls);
+        // Values must not point to the dropped list anymore.
+        unsafe {
+            assert!(a.cur.as_ref().prev_next.get().is_none());
+            assert!(b.cur.as_ref().prev_next.get().is_none());
+        }
+        Handle::detach(&a);
+        drop(b);
+        assert_eq!(*a, 1);
+    }
+
+    #[test]
+    fn iter_test() {
+        let ls = WeakList::new();
+        let h1 = ls.new_elem(1);
+        let h2 = ls.new_elem(2);
+        let h3 = ls.new_elem(3);
+        let _h3 = Handle::clone(&h3);
+
+        let mut it = ls.iter();
+        assert_eq!(*it.next().unwrap(), 3);
+        drop(h2); // Drop the next value during iteration.
+        Handle::detach(&h1);
+        assert_eq!(*it.next().unwrap(), 2); // Still alive.
+        assert!(it.next().is_none());
+        assert_eq!(ls.iter().map(|h| *h).collect::<Vec<_>>(), [3]);
+
+        let counts = ls.iter_with_counts()
+            .map(|(h, count)| (*h, count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [(3, 2)]);
+    }
+
+    #[test]
+    fn clear_if_test() {
+        let ls = WeakList::new();
+        let _h = (ls.new_elem(1), ls.new_elem(2));
+        assert!(!ls.is_empty());
+
+        assert!(!ls.clear_if(|ls| ls.len() > 2));
+        assert_eq!(ls.len(), 2);
+        assert!(ls.clear_if(|ls| ls.len() > 1));
+        assert_eq!(ls.len(), 0);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn same_or_equal_test() {
+        struct P(i32, Rc<Cell<usize>>);
+        impl PartialEq for P {
+            fn eq(&self, other: &P) -> bool {
+                self.1.set(self.1.get() + 1);
+                self.0 == other.0
+            }
+        }
+
+        let cmps = Rc::new(Cell::new(0));
+        let ls = WeakList::new();
+        let a = ls.new_elem(P(1, Rc::clone(&cmps)));
+        let b = ls.new_elem(P(1, Rc::clone(&cmps)));
+        let c = ls.new_elem(P(2, Rc::clone(&cmps)));
+
+        assert!(Handle::ptr_eq(&a, &Handle::clone(&a)));
+        assert!(!Handle::ptr_eq(&a, &b));
+        assert!(Handle::same_or_equal(&a, &Handle::clone(&a)));
+        assert_eq!(cmps.get(), 0); // Short-circuited.
+        assert!(Handle::same_or_equal(&a, &b));
+        assert!(!Handle::same_or_equal(&a, &c));
+        assert_eq!(cmps.get(), 2);
+    }
+
+    #[test]
+    fn to_linked_list_test() {
+        let ls = WeakList::new();
+        ls.new_elem(1);
+        let _h2 = ls.new_elem(2);
+        let _h3 = ls.new_elem(3);
+
+        let mut linked = ls.to_linked_list();
+        assert_eq!(linked.iter().map(|h| **h).collect::<Vec<_>>(), [3, 2]);
+        drop((_h2, _h3));
+        assert_eq!(ls.len(), 2); // Kept alive by `linked`.
+        linked.pop_front();
+        assert_eq!(ls.len(), 1);
+    }
+
+    #[test]
+    fn reserve_pool_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.pool_capacity(), 0);
+        ls.reserve_pool(2);
+        assert_eq!(ls.pool_capacity(), 2);
+
+        let h1 = ls.new_elem(1);
+        assert_eq!(ls.pool_capacity(), 1);
+        let h2 = ls.new_elem(2);
+        let h3 = ls.new_elem(3); // Out of pool.
+        assert_eq!(ls.pool_capacity(), 0);
+        assert_eq!((*h1, *h2, *h3), (1, 2, 3));
+        drop(h2);
+        assert_eq!(ls.pool_capacity(), 0);
+        assert_eq!(ls.iter().map(|h| *h).collect::<Vec<_>>(), [3, 1]);
+        ls.reserve_pool(4);
+        ls.shrink_to_fit();
+        assert_eq!(ls.pool_capacity(), 0);
+        assert_eq!(values(&ls), [3, 1]);
+
+        let ls = WeakList::with_capacity(2);
+        assert_eq!(ls.pool_capacity(), 2);
+        let _h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        assert_eq!(ls.pool_capacity(), 0);
+        assert_eq!(ls.len(), 3);
+        ls.reserve_pool(1);
+        assert_eq!(ls.capacity_report(), CapacityReport {
+            live: 3,
+            pooled: 1,
+            node_size: ::std::mem::size_of::<Node<i32>>(),
+        });
+    }
+
+    #[test]
+    fn upgrade_all_capacity_test() {
+        let ls = WeakList::new();
+        let _h = (0..100).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let v = ls.upgrade_all();
+        assert_eq!(v.len(), 100);
+        assert_eq!(v.capacity(), 100); // Allocated exactly once.
+    }
+
+    #[test]
+    fn position_test() {
+        let ls = WeakList::new();
+        let h = [ls.new_elem(1), ls.new_elem(2), ls.new_elem(3)];
+        assert_eq!(ls.position(|&x| x == 1), Some(2));
+        assert_eq!(ls.position(|&x| x < 3), Some(1));
+        assert_eq!(ls.position(|&x| x == 4), None);
+        Handle::detach(&h[1]);
+        assert_eq!(ls.position(|&x| x == 1), Some(1));
+    }
+
+    #[test]
+    fn rotate_right_test() {
+        let ls = WeakList::new();
+        ls.rotate_right(1); // No-op on empty list.
+        let _h = (0..5).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        for n in 0..12 {
+            let mut expect = values(&ls);
+            expect.rotate_right(n % 5);
+            ls.rotate_right(n);
+            assert_eq!(values(&ls), expect);
+            assert_consistent(&ls);
+        }
+    }
+
+    #[test]
+    fn retain_test() {
+        let ls = WeakList::new();
+        let h = (0..6).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        assert_eq!(ls.retain(|&x| x != 3), 1);
+        assert_eq!(values(&ls), [5, 4, 2, 1, 0]);
+        assert_eq!(ls.retain_with_index(|i, _| i % 2 == 0), 2);
+        assert_eq!(values(&ls), [5, 2, 0]);
+        assert_consistent(&ls);
+
+        // Modify the list inside the predicate.
+        let h = RefCell::new(h);
+        let removed = ls.retain(|&x| {
+            h.borrow_mut().retain(|h| **h != 2);
+            x != 0
+        });
+        // `2` is removed by dropping, not by `retain`.
+        assert_eq!(removed, 1);
+        assert_eq!(values(&ls), [5]);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn try_unwrap_test() {
+        let ls = WeakList::new();
+        let h1 = ls.new_elem(1);
+        let h2 = ls.new_elem(2);
+        let h2_ = Handle::clone(&h2);
+
+        let h2 = Handle::try_into_box(h2).unwrap_err();
+        assert_eq!(values(&ls), [2, 1]); // Nothing happened.
+        drop(h2_);
+        assert_eq!(Handle::try_into_box(h2).ok(), Some(Box::new(2)));
+        assert_eq!(Handle::try_unwrap(h1).ok(), Some(1));
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn total_strong_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.total_strong(), 0);
+        let h1 = ls.new_elem(1);
+        let h2 = ls.new_elem(2);
+        let _h = (Handle::clone(&h1), Handle::clone(&h1), Handle::clone(&h2));
+        assert_eq!(ls.len(), 2);
+        assert_eq!(ls.total_strong(), 5);
+    }
+
+    #[test]
+    fn iter_values_test() {
+        struct C(i32, Rc<Cell<usize>>);
+        impl Clone for C {
+            fn clone(&self) -> C {
+                self.1.set(self.1.get() + 1);
+                C(self.0, Rc::clone(&self.1))
+            }
+        }
+
+        let clones = Rc::new(Cell::new(0));
+        let ls = WeakList::new();
+        let _h = (0..3).map(|i| ls.new_elem(C(i, Rc::clone(&clones)))).collect::<Vec<_>>();
+        assert_eq!(ls.iter_values().map(|c| c.0).collect::<Vec<_>>(), [2, 1, 0]);
+        assert_eq!(clones.get(), 3);
+        assert_eq!(ls.iter_values().next().map(|c| c.0), Some(2));
+        assert_eq!(clones.get(), 4);
+    }
+
+    #[test]
+    fn swap_test() {
+        let ls = WeakList::new();
+        let _h = (0..5).rev().map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let mut expect = values(&ls);
+        assert_eq!(expect, [0, 1, 2, 3, 4]);
+        for &(a, b) in &[(1, 2), (3, 2), (0, 4), (4, 0), (1, 3), (0, 1), (3, 4), (2, 2)] {
+            ls.swap(a, b);
+            expect.swap(a, b);
+            assert_eq!(values(&ls), expect);
+            assert_consistent(&ls);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn swap_out_of_bounds_test() {
+        let ls = WeakList::new();
+        let _h = ls.new_elem(0);
+        ls.swap(0, 1);
+    }
+
+    #[test]
+    fn extract_if_test() {
+        let ls = WeakList::new();
+        let _h = (0..6).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        {
+            let mut it = ls.extract_if(|&x| x % 2 == 0);
+            assert_eq!(it.next().map(|h| *h), Some(4));
+            assert_eq!(it.next().map(|h| *h), Some(2));
+        }
+        assert_eq!(values(&ls), [5, 3, 1, 0]);
+        assert_consistent(&ls);
+        let v = ls.extract_if(|&x| x < 4).map(|h| *h).collect::<Vec<_>>();
+        assert_eq!(v, [3, 1, 0]);
+        assert_eq!(values(&ls), [5]);
+    }
+
+    #[test]
+    fn first_test() {
+        let ls = WeakList::new();
+        assert!(ls.first().is_none());
+        let _h1 = ls.new_elem(1);
+        let h2 = ls.new_elem(2);
+        assert_eq!(ls.first().map(|h| *h), Some(2));
+        drop(h2);
+        assert_eq!(ls.first().map(|h| *h), Some(1));
+    }
+
+    #[test]
+    fn weak_test() {
+        let buf = Rc::new(RefCell::new(vec![]));
+        let ls = WeakList::new();
+        let h = ls.new_elem(S { value: 1, buf: Rc::clone(&buf) });
+        let w = Handle::downgrade(&h);
+        let w2 = Weak::clone(&w);
+        assert_eq!(Handle::weak_count(&h), 2);
+        assert_eq!(w.upgrade().map(|h| h.value), Some(1));
+        assert_eq!(Handle::strong_count(&h), 1);
+        assert!(Handle::ptr_eq_weak(&h, &w2));
+        assert!(!Handle::ptr_eq_weak(&ls.new_elem(S { value: 0, buf: Rc::clone(&buf) }), &w2));
+        assert_eq!(buf.borrow_mut().pop(), Some(0));
+        drop(w2);
+
+        drop(h); // The `Weak` doesn't keep the value alive.
+        assert_eq!(*buf.borrow(), [1]);
+        assert!(ls.is_empty());
+        assert!(w.upgrade().is_none());
+
+        let h = ls.new_elem(S { value: 2, buf: Rc::clone(&buf) });
+        let w = Handle::downgrade(&h);
+        let s = Handle::try_unwrap(h).ok().unwrap();
+        assert!(w.upgrade().is_none());
+        assert_eq!(s.value, 2);
+
+        let h = ls.new_elem(S { value: 3, buf: Rc::clone(&buf) });
+        let w = Handle::downgrade(&h);
+        let h2 = Handle::clone(&h);
+        let h = Handle::drop_value(h).err().unwrap();
+        drop(h2);
+        assert!(Handle::drop_value(h).is_ok());
+        assert_eq!(buf.borrow_mut().pop(), Some(3));
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn iter_clone_test() {
+        let ls = WeakList::new();
+        let _h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let mut it = ls.iter();
+        it.next();
+        let it2 = it.clone();
+        assert_eq!(it2.map(|h| *h).collect::<Vec<_>>(), [1, 0]);
+        assert_eq!(it.map(|h| *h).collect::<Vec<_>>(), [1, 0]);
+    }
+
+    #[test]
+    fn upgrade_all_into_test() {
+        let ls = WeakList::new();
+        let _h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let mut v = Vec::with_capacity(8);
+        let buf = v.as_ptr();
+        ls.upgrade_all_into(&mut v);
+        ls.upgrade_all_into(&mut v);
+        assert_eq!(v.iter().map(|h| **h).collect::<Vec<_>>(), [2, 1, 0]);
+        assert_eq!(v.as_ptr(), buf); // No reallocation.
+    }
+
+    #[test]
+    fn map_collect_test() {
+        let ls = WeakList::new();
+        let h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let (ls2, h2) = ls.map_collect(|&x| x * 10);
+        assert_eq!(values(&ls2), [20, 10, 0]);
+        assert_eq!(h2.iter().map(|h| **h).collect::<Vec<_>>(), [20, 10, 0]);
+        drop(h);
+        assert_eq!(values(&ls2), [20, 10, 0]); // Independent.
+        drop(h2);
+        assert!(ls2.is_empty());
+    }
+
+    #[test]
+    fn ord_test() {
+        use std::collections::BTreeSet;
+
+        let ls = WeakList::new();
+        let set = [3, 1, 2, 1].iter()
+            .map(|&x| ls.new_elem(x))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(set.iter().map(|h| **h).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(ls.new_elem(1) == ls.new_elem(1));
+        assert!(ls.new_elem(1) < ls.new_elem(2));
+    }
+
+    #[test]
+    fn split_at_test() {
+        let ls = WeakList::new();
+        let _h = (0..4).rev().map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let (a, b) = ls.split_at(1);
+        assert_eq!((values(&a), values(&b)), (vec![0], vec![1, 2, 3]));
+        let (b, c) = b.split_at(0);
+        assert_eq!((values(&b), values(&c)), (vec![], vec![1, 2, 3]));
+        let (c, d) = c.split_at(3);
+        assert_eq!((values(&c), values(&d)), (vec![1, 2, 3], vec![]));
+        assert_consistent(&a);
+        assert_consistent(&c);
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_at_out_of_bounds_test() {
+        let ls = WeakList::new();
+        let _h = ls.new_elem(0);
+        ls.split_at(2);
+    }
+
+    #[test]
+    fn upgrade_by_id_test() {
+        let ls = WeakList::new();
+        let h1 = ls.new_elem(1);
+        let h2 = ls.new_elem(2);
+        assert_ne!(Handle::id(&h1), Handle::id(&h2));
+        assert_eq!(Handle::id(&h1), Handle::id(&Handle::clone(&h1)));
+
+        let h = ls.upgrade_by_id(Handle::id(&h1)).unwrap();
+        assert!(Handle::ptr_eq(&h, &h1));
+        Handle::detach(&h1);
+        assert!(ls.upgrade_by_id(Handle::id(&h1)).is_none());
+        assert!(ls.upgrade_by_id(0).is_none());
+    }
+
+    #[test]
+    fn attach_test() {
+        let ls = WeakList::new();
+        let ls2 = WeakList::new();
+        let h1 = ls.new_elem(1);
+        let _h2 = ls.new_elem(2);
+        assert!(Handle::is_linked(&h1));
+        Handle::detach(&h1);
+        assert!(!Handle::is_linked(&h1));
+        ls.attach(&h1);
+        assert!(Handle::is_linked(&h1));
+        assert_eq!(values(&ls), [1, 2]);
+
+        ls2.attach(&h1); // Moved.
+        assert_eq!(values(&ls), [2]);
+        assert_eq!(values(&ls2), [1]);
+        assert_consistent(&ls);
+        assert_consistent(&ls2);
+    }
+
+    #[test]
+    fn chunks_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.chunks(2).count(), 0);
+        let _h = (0..5).rev().map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let chunks = ls.chunks(2)
+            .map(|c| c.iter().map(|h| **h).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, [vec![0, 1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_zero_test() {
+        let _ = WeakList::<i32>::new().chunks(0);
+    }
 
-            handles.remove(0); // Drop handle to `1`.
-            assert_eq!(get_values(&handles), [2]);
-            assert_eq!(get_snapshot(), [2]);
-            assert_eq!(get_last_dropped(), [1]);
+    #[test]
+    fn set_ops_test() {
+        let a = WeakList::new();
+        let b = WeakList::new();
+        let _ha = [1, 2, 3, 2].iter().map(|&x| a.new_elem(x)).collect::<Vec<_>>();
+        let _hb = [4, 3, 2].iter().map(|&x| b.new_elem(x)).collect::<Vec<_>>();
+        let get = |v: Vec<Handle<i32>>| v.iter().map(|h| **h).collect::<Vec<_>>();
+        assert_eq!(get(a.intersection(&b)), [2, 3]);
+        assert_eq!(get(a.union(&b)), [2, 3, 1, 4]);
+        assert_eq!(get(a.intersection(&WeakList::new())), []);
+    }
 
-            ls.clear();
-            assert_eq!(get_snapshot(), []);
-            assert_eq!(get_last_dropped(), []); // `clear` never cause drop.
+    #[test]
+    fn get_mut_test() {
+        let ls = WeakList::new();
+        let mut h = ls.new_elem(1);
+        assert!(Handle::get_mut(&mut h).is_none()); // In the list.
+        Handle::detach(&h);
+        let h2 = Handle::clone(&h);
+        assert!(Handle::modify(&mut h, |x| *x += 1).is_none()); // Shared.
+        drop(h2);
+        let w = Handle::downgrade(&h);
+        assert!(Handle::get_mut(&mut h).is_none()); // Has `Weak`.
+        drop(w);
+        assert_eq!(Handle::modify(&mut h, |x| { *x += 1; *x }), Some(2));
+        assert_eq!(*h, 2);
+        assert_eq!(Handle::with_mut(&mut h, |x| { *x *= 3; *x }), Some(6));
+        assert_eq!(Handle::with(&h, |x| x + 1), 7);
+    }
 
-            handles.clear();
-            assert_eq!(get_last_dropped(), [2]);
+    #[test]
+    fn live_len_test() {
+        let ls = WeakList::new();
+        let _h = ls.new_elem(1);
+        ls.new_elem(2);
+        assert_eq!(ls.live_len(), 1);
+        assert_eq!(values(&ls), [1]);
+        assert_eq!(ls.upgrade_all().len(), 1);
+        assert_eq!(ls.sweep(), 0);
+        assert_eq!(ls.len(), 1);
+    }
 
-            handles.push(ls.new_elem(new_s(5)));
-            handles = ls.take_all();
-            assert_eq!(get_values(&handles), [5]);
-            assert_eq!(get_snapshot(), []); // `take_all` remove all elems from list.
-            assert_eq!(get_last_dropped(), []);
+    #[test]
+    fn extend_from_handles_test() {
+        let ls = WeakList::new();
+        let h = (0..4).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let taken = ls.take_all();
+        assert!(ls.is_empty());
+        ls.extend_from_handles(taken);
+        assert_eq!(values(&ls), [3, 2, 1, 0]);
+        assert_consistent(&ls);
 
-            h5 = Handle::clone(&handles[0]);
+        let again = vec![Handle::clone(&h[1]), Handle::clone(&h[1]), Handle::clone(&h[0])];
+        ls.extend_from_handles(again);
+        assert_eq!(values(&ls), [1, 0, 3, 2]);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn from_handles_test() {
+        let ls = WeakList::new();
+        let h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let ls2 = WeakList::from_handles(h.iter().cloned());
+        assert!(ls.is_empty());
+        assert_eq!(values(&ls2), [0, 1, 2]);
+
+        let ls3 = WeakList::from_handles(vec![ls2.new_elem(3)]);
+        assert!(ls3.is_empty()); // The only `Handle` is dropped.
+    }
+
+    #[test]
+    fn reduce_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.reduce(|a: String, b| a + b), None);
+        let _h = ["c", "b", "a"].iter().map(|&x| ls.new_elem(x.to_owned())).collect::<Vec<_>>();
+        assert_eq!(ls.reduce(|a, b| a + b), Some("abc".to_owned()));
+    }
+
+    #[test]
+    fn reentrant_drop_test() {
+        struct N {
+            value: i32,
+            list: Rc<WeakList<N>>,
+            weak: RefCell<Option<Weak<N>>>,
+            seen: Rc<RefCell<Vec<Vec<i32>>>>,
+        }
+        impl Drop for N {
+            fn drop(&mut self) {
+                let weak = self.weak.borrow_mut().take().unwrap();
+                assert!(weak.upgrade().is_none());
+                let values = self.list.iter().map(|h| h.value).collect();
+                self.seen.borrow_mut().push(values);
+            }
         }
-        assert_eq!(h5.value, 5); // Handle can outlive the list
-        assert_eq!(get_last_dropped(), []);
 
-        drop(h5);
-        assert_eq!(get_last_dropped(), [5]);
+        let ls = Rc::new(WeakList::new());
+        let seen = Rc::new(RefCell::new(vec![]));
+        let mut h = (0..3).map(|value| {
+            let h = ls.new_elem(N {
+                value,
+                list: Rc::clone(&ls),
+                weak: RefCell::new(None),
+                seen: Rc::clone(&seen),
+            });
+            *h.weak.borrow_mut() = Some(Handle::downgrade(&h));
+            h
+        }).collect::<Vec<_>>();
+        h.remove(1);
+        h.clear();
+        assert_eq!(*seen.borrow(), [vec![2, 0], vec![2], vec![]]);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn windows_test() {
+        let ls = WeakList::new();
+        let _h = (0..4).rev().map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let get = |n| ls.windows(n)
+            .map(|w| w.iter().map(|h| **h).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(get(3), [[0, 1, 2], [1, 2, 3]]);
+        assert_eq!(get(1), [[0], [1], [2], [3]]);
+        assert_eq!(get(5).len(), 0);
+    }
+
+    #[test]
+    fn retain_handles_test() {
+        let ls = WeakList::new();
+        ls.new_elem(0);
+        let _h = (1..5).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let mut odd = vec![];
+        ls.retain_handles(|h| if *h % 2 == 1 { odd.push(h); false } else { true });
+        assert_eq!(odd.iter().map(|h| **h).collect::<Vec<_>>(), [3, 1]);
+        assert_eq!(values(&ls), [4, 2]);
+        assert_consistent(&ls);
+
+        drop(_h); // Kept by `odd` only.
+        assert!(ls.is_empty());
+        assert_eq!(*odd[0], 3);
+
+        // Values moved to another list by `f` are skipped, and later ones
+        // are still visited.
+        let other = WeakList::new();
+        let v = ls.extend_back(vec![1, 2, 3]);
+        let removed = ls.retain_handles(|h| {
+            if *h == 1 {
+                other.attach(&v[1]);
+            }
+            false
+        });
+        assert_eq!(removed, 2);
+        assert!(ls.is_empty());
+        assert_eq!(values(&other), [2]);
+    }
+
+    #[test]
+    fn for_each_while_test() {
+        let ls = WeakList::new();
+        let _h = (0..5).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        let mut seen = vec![];
+        ls.for_each_while(|&x| {
+            seen.push(x);
+            if x == 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(seen, [4, 3, 2]);
+    }
+
+    #[test]
+    fn as_ref_test() {
+        fn len<S: AsRef<str>>(s: S) -> usize {
+            s.as_ref().len()
+        }
+
+        let ls = WeakList::new();
+        let h = ls.new_elem("foo".to_owned());
+        assert_eq!(len(&*h), 3);
+        assert_eq!(len(h), 3);
+    }
+
+    #[test]
+    fn clone_handles_into_test() {
+        let a = WeakList::new();
+        let b = WeakList::new();
+        let _h = (a.new_elem(1), a.new_elem(2), b.new_elem(3));
+        let mut v = a.clone_handles();
+        b.clone_handles_into(&mut v);
+        a.clone_handles_into(&mut v);
+        assert_eq!(v.iter().map(|h| **h).collect::<Vec<_>>(), [2, 1, 3, 2, 1]);
+    }
+
+    #[test]
+    fn is_first_last_test() {
+        let ls = WeakList::new();
+        let ls2 = WeakList::new();
+        let h = (0..3).map(|i| ls.new_elem(i)).collect::<Vec<_>>();
+        assert!(Handle::is_first_in(&h[2], &ls));
+        assert!(!Handle::is_first_in(&h[1], &ls));
+        assert!(Handle::is_last_in(&h[0], &ls));
+        assert!(!Handle::is_last_in(&h[1], &ls));
+        assert!(!Handle::is_first_in(&h[2], &ls2));
+        assert!(!Handle::is_last_in(&h[0], &ls2));
+    }
+
+    #[test]
+    fn take_skip_while_test() {
+        let ls = WeakList::new();
+        let h = [3, 2, 1, 4, 5].iter().rev().map(|&x| ls.new_elem(x)).collect::<Vec<_>>();
+        let mut called = 0;
+        let v = ls.take_while(|&x| { called += 1; x < 3 });
+        assert_eq!(v.iter().map(|h| **h).collect::<Vec<_>>(), [] as [i32; 0]);
+        assert_eq!(called, 1);
+
+        let get = |v: Vec<Handle<i32>>| v.iter().map(|h| **h).collect::<Vec<_>>();
+        assert_eq!(get(ls.take_while(|&x| x > 1)), [3, 2]);
+        assert_eq!(get(ls.skip_while(|&x| x > 1)), [1, 4, 5]);
+        let v = ls.take_while(|&x| x > 2);
+        assert_eq!(get(v), [3]);
+        assert!(h.iter().all(|h| Handle::strong_count(h) == 1)); // The rest are not kept.
+    }
+
+    #[test]
+    fn clear_test() {
+        let buf = Rc::new(RefCell::new(vec![]));
+        let ls = WeakList::new();
+        let h = (0..3)
+            .map(|value| ls.new_elem(S { value, buf: Rc::clone(&buf) }))
+            .collect::<Vec<_>>();
+        let w = Handle::downgrade(&h[1]);
+        ls.clear();
+        assert!(ls.is_empty());
+        assert!(buf.borrow().is_empty());
+        assert!(h.iter().all(|h| !Handle::is_linked(h) && Handle::strong_count(h) == 1));
+        assert_eq!(w.upgrade().map(|h| h.value), Some(1));
+
+        ls.attach(&h[0]);
+        drop(ls); // Values outlive the list.
+        assert!(!Handle::is_linked(&h[0]));
+        drop(h);
+        assert_eq!(*buf.borrow(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn insert_order_test() {
+        let ls = WeakList::with_order(InsertOrder::Front);
+        let v: Vec<_> = (0..3).map(|i| ls.new_elem(i)).collect();
+        assert_eq!(values(&ls), [2, 1, 0]);
+        assert_consistent(&ls);
+
+        let ls = WeakList::with_order(InsertOrder::Back);
+        let w: Vec<_> = (0..3).map(|i| ls.new_elem(i)).collect();
+        assert_eq!(values(&ls), [0, 1, 2]);
+        assert_consistent(&ls);
+        drop(v);
+        drop(w);
+        assert!(ls.is_empty());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn debug_dump_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.debug_dump(), "head: none\ntail: none\n");
+
+        let a = ls.new_elem("a");
+        let b = ls.new_elem("b");
+        let _b2 = b.clone();
+        assert_eq!(ls.debug_dump(), "\
+head: #0
+#0: \"b\" (strong: 2)
+#1: \"a\" (strong: 1)
+tail: #1
+");
+        drop(a);
+        assert_eq!(ls.debug_dump(), "head: #0\n#0: \"b\" (strong: 2)\ntail: #0\n");
+    }
+
+    #[test]
+    fn new_elem_weak_test() {
+        let ls = WeakList::new();
+        let (h, w) = ls.new_elem_weak(1);
+        assert_eq!(*w.upgrade().unwrap(), 1);
+        assert_eq!(values(&ls), [1]);
+        drop(h);
+        assert!(w.upgrade().is_none());
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn unwind_safe_test() {
+        use std::panic::catch_unwind;
+
+        let ls = WeakList::new();
+        let a = ls.new_elem(1);
+        let b = ls.new_elem(2);
+        let ret = catch_unwind(|| {
+            let _c = ls.new_elem(3);
+            assert_eq!(values(&ls), [3, 2, 1]);
+            Handle::detach(&b);
+            if *a == 1 {
+                panic!("boom");
+            }
+        });
+        assert!(ret.is_err());
+        assert_eq!(values(&ls), [1]);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn eq_by_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem((1, "a"));
+        let b = ls.new_elem((1, "b"));
+        let c = ls.new_elem((2, "a"));
+        assert!(Handle::eq_by(&a, &b, |x, y| x.0 == y.0));
+        assert!(!Handle::eq_by(&a, &c, |x, y| x.0 == y.0));
+        assert!(Handle::eq_by(&a, &a.clone(), |_, _| panic!("called")));
+    }
+
+    #[test]
+    fn retain_owned_test() {
+        let ls = WeakList::new();
+        let other = WeakList::new();
+        let mut owners: Vec<_> = (0..5).map(|i| ls.new_elem(i)).collect();
+        owners.push(other.new_elem(1));
+        ls.retain_owned(&mut owners, |&x| x % 2 == 0);
+        assert_eq!(values(&ls), [4, 2, 0]);
+        assert_eq!(owners.iter().map(|h| **h).collect::<Vec<_>>(), [0, 2, 4, 1]);
+        assert_eq!(values(&other), [1]);
+        assert_consistent(&ls);
+
+        // `2` is moved to `other` by `f`, so neither it nor its owner is removed.
+        let two = Handle::clone(&owners[1]);
+        let removed = ls.retain_owned(&mut owners, |&x| {
+            if x == 4 {
+                other.attach(&two);
+            }
+            x == 4
+        });
+        assert_eq!(removed, 1);
+        assert_eq!(values(&ls), [4]);
+        assert_eq!(values(&other), [2, 1]);
+        assert_eq!(owners.iter().map(|h| **h).collect::<Vec<_>>(), [2, 4, 1]);
+        assert_consistent(&other);
+    }
+
+    #[test]
+    fn builder_test() {
+        let mut b = WeakList::builder();
+        assert_eq!(**b.push(1), 1);
+        b.push(2);
+        b.push(3);
+        let (ls, handles) = b.finish();
+        assert_eq!(values(&ls), [1, 2, 3]);
+        assert_eq!(handles.iter().map(|h| **h).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_consistent(&ls);
+        drop(handles);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn try_clone_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem(1);
+        let b = Handle::try_clone(&a).unwrap();
+        assert_eq!(Handle::strong_count(&a), 2);
+
+        let count = unsafe { &a.cur.as_ref().strong_count };
+        count.set(usize::MAX);
+        assert_eq!(Handle::try_clone(&a).err(), Some(CloneError));
+        assert_eq!(count.get(), usize::MAX);
+        count.set(2);
+        drop(b);
+        assert_eq!(Handle::strong_count(&a), 1);
+    }
+
+    #[test]
+    fn iter_rev_test() {
+        let ls = WeakList::new();
+        let v: Vec<_> = (0..4).map(|i| ls.new_elem(i)).collect();
+        let fwd: Vec<_> = ls.iter().map(|h| *h).collect();
+        let mut rev: Vec<_> = ls.iter_rev().map(|h| *h).collect();
+        rev.reverse();
+        assert_eq!(fwd, rev);
+
+        let mut it = ls.iter_rev();
+        drop(v);
+        assert_eq!(it.next().map(|h| *h), Some(0));
+        drop(it);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn niche_test() {
+        use std::mem::size_of;
+        assert_eq!(size_of::<Option<Handle<S>>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<Weak<S>>>(), size_of::<usize>());
+    }
+
+    #[test]
+    fn max_len_test() {
+        let ls = WeakList::with_max_len(2);
+        let a = ls.new_elem(1);
+        let b = ls.new_elem(2);
+        assert_eq!(values(&ls), [2, 1]);
+        let c = ls.new_elem(3);
+        assert_eq!(values(&ls), [3, 2]);
+        assert!(!Handle::is_linked(&a));
+        assert_eq!(*a, 1);
+        assert_consistent(&ls);
+
+        drop(b);
+        let _d = ls.new_elem(4);
+        assert_eq!(values(&ls), [4, 3]);
+        drop(c);
+
+        let ls = WeakList::with_max_len(0);
+        let e = ls.new_elem(5);
+        assert!(ls.is_empty());
+        assert_eq!(*e, 5);
+    }
+
+    #[test]
+    fn max_len_back_test() {
+        let ls = WeakList::with_order_and_max_len(InsertOrder::Back, 2);
+        let a = ls.new_elem(1);
+        let _b = ls.new_elem(2);
+        assert_eq!(values(&ls), [1, 2]);
+        let _c = ls.new_elem(3);
+        assert_eq!(values(&ls), [2, 3]);
+        assert!(!Handle::is_linked(&a));
+        assert_eq!(*a, 1);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn raw_hash_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem(1);
+        let b = ls.new_elem(1);
+        assert_eq!(Handle::raw_hash(&a), Handle::raw_hash(&a.clone()));
+        assert_ne!(Handle::raw_hash(&a), Handle::raw_hash(&b));
+    }
+
+    #[test]
+    fn iter_rev_drop_test() {
+        let ls = WeakList::new();
+        let mut v: Vec<_> = (0..20).map(|i| Some(ls.new_elem(i))).collect();
+        let mut seen = Vec::new();
+        for h in ls.iter_rev() {
+            let x = *h as usize;
+            seen.push(x);
+            // Drop the yielded value and some of the others not reached yet.
+            drop(h);
+            v[x] = None;
+            if x % 3 == 1 && x + 1 < v.len() {
+                v[x + 1] = None;
+            }
+            assert_consistent(&ls);
+        }
+        assert_eq!(seen, (0..20).collect::<Vec<_>>());
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn splice_test() {
+        let ls = WeakList::new();
+        let other = WeakList::new();
+        let v: Vec<_> = (0..3).map(|i| ls.new_elem(i)).collect();
+        let w: Vec<_> = (10..12).map(|i| other.new_elem(i)).collect();
+        ls.splice(1, &other);
+        assert_eq!(values(&ls), [2, 11, 10, 1, 0]);
+        assert!(other.is_empty());
+        assert_consistent(&ls);
+
+        w.iter().for_each(|h| other.attach(h));
+        ls.splice(0, &other);
+        assert_eq!(values(&ls), [11, 10, 2, 1, 0]);
+        assert_consistent(&ls);
+
+        w.iter().for_each(|h| other.attach(h));
+        ls.splice(3, &other);
+        assert_eq!(values(&ls), [2, 1, 0, 11, 10]);
+        assert_consistent(&ls);
+
+        ls.splice(5, &ls);
+        assert_eq!(values(&ls), [2, 1, 0, 11, 10]);
+        assert_consistent(&other);
+        drop(v);
+        assert_eq!(values(&ls), [11, 10]);
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem(1);
+        let _b = a.clone();
+        assert_eq!(Handle::snapshot(&a), (2, &1));
+    }
+
+    #[test]
+    fn extend_front_back_test() {
+        let ls = WeakList::new();
+        let a = ls.extend_back(vec![1, 2, 3]);
+        assert_eq!(values(&ls), [1, 2, 3]);
+        let b = ls.extend_front(vec![4, 5]);
+        assert_eq!(values(&ls), [5, 4, 1, 2, 3]);
+        let c = ls.extend_back(vec![6]);
+        assert_eq!(values(&ls), [5, 4, 1, 2, 3, 6]);
+        assert_consistent(&ls);
+        let all = a.iter().chain(&b).chain(&c).map(|h| **h).collect::<Vec<_>>();
+        assert_eq!(all, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn handle_new_test() {
+        let a = Handle::new(1);
+        assert!(!Handle::is_linked(&a));
+        assert_eq!(*a, 1);
+        let b = a.clone();
+        assert_eq!(Handle::strong_count(&a), 2);
+        let a = Handle::try_unwrap(a).unwrap_err();
+        drop(b);
+        assert_eq!(Handle::try_unwrap(a).ok(), Some(1));
+
+        let ls = WeakList::new();
+        let c: Handle<i32> = Handle::default();
+        ls.attach(&c);
+        assert_eq!(ls.upgrade_all().iter().map(|h| **h).collect::<Vec<_>>(), [0]);
+        drop(c);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn iter_lazy_test() {
+        let ls = WeakList::new();
+        let v: Vec<_> = (0..4).map(|i| ls.new_elem(i)).collect();
+        let mut it = ls.iter();
+        let h = it.next().unwrap();
+        // Only the yielded value and the next one are upgraded.
+        assert_eq!(Handle::strong_count(&v[3]), 2);
+        assert_eq!(Handle::strong_count(&v[2]), 2);
+        assert_eq!(Handle::strong_count(&v[1]), 1);
+        drop(h);
+        drop(it);
+        assert_eq!(values(&ls), [3, 2, 1, 0]);
+
+        for h in ls.iter() {
+            if *h == 2 {
+                break;
+            }
+        }
+        assert_eq!(ls.iter().count(), 4);
+        assert_eq!(values(&ls), [3, 2, 1, 0]);
+        assert!(v.iter().all(|h| Handle::strong_count(h) == 1));
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn retain_try_test() {
+        let ls = WeakList::new();
+        let v: Vec<_> = (0..6).map(|i| ls.new_elem(i)).collect();
+        let mut called = 0;
+        let removed = ls.retain_try(|&x| {
+            called += 1;
+            if x == 2 { ControlFlow::Break(()) } else { ControlFlow::Continue(x % 2 == 0) }
+        });
+        assert_eq!(removed, 2);
+        assert_eq!(called, 4);
+        assert_eq!(values(&ls), [4, 2, 1, 0]);
+        assert_consistent(&ls);
+
+        // Detaching the next value does not end the pass early.
+        let removed = ls.retain_try(|&x| {
+            if x == 4 {
+                Handle::detach(&v[2]);
+            }
+            if x == 0 { ControlFlow::Break(()) } else { ControlFlow::Continue(x == 4) }
+        });
+        assert_eq!(removed, 1);
+        assert_eq!(values(&ls), [4, 0]);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn values_eq_test() {
+        let ls = WeakList::new();
+        assert!(ls.values_eq(&[]));
+        let _v: Vec<_> = (0..3).map(|i| ls.new_elem(i)).collect();
+        assert!(ls.values_eq(&[2, 1, 0]));
+        assert!(!ls.values_eq(&[2, 1]));
+        assert!(!ls.values_eq(&[2, 1, 0, 0]));
+        assert!(!ls.values_eq(&[2, 0, 1]));
+    }
+
+    #[test]
+    fn first_last_where_test() {
+        let ls = WeakList::new();
+        let _v: Vec<_> = (0..6).map(|i| ls.new_elem(i)).collect();
+        assert_eq!(ls.first_where(|&x| x % 2 == 1).map(|h| *h), Some(5));
+        assert_eq!(ls.last_where(|&x| x % 2 == 1).map(|h| *h), Some(1));
+        assert!(ls.first_where(|&x| x > 5).is_none());
+        assert!(ls.last_where(|&x| x > 5).is_none());
+    }
+
+    #[test]
+    fn eq_as_set_test() {
+        let (a, b) = (WeakList::new(), WeakList::new());
+        let _v: Vec<_> = [1, 2, 2, 3].iter().map(|&x| a.new_elem(x)).collect();
+        let mut w: Vec<_> = [2, 3, 1, 2].iter().map(|&x| b.new_elem(x)).collect();
+        assert!(a.eq_as_set(&b));
+        assert!(a.eq_as_set(&a));
+        w.pop();
+        w.push(b.new_elem(1));
+        assert!(!a.eq_as_set(&b));
+        w.pop();
+        assert!(!a.eq_as_set(&b));
+
+        // Duplicates are counted, not just checked for presence.
+        let (c, d) = (WeakList::new(), WeakList::new());
+        let _x = c.extend_back(vec![1, 1, 2]);
+        let _y = d.extend_back(vec![1, 2, 2]);
+        assert!(!c.eq_as_set(&d));
+    }
+
+    #[test]
+    fn unwrap_if_unique_test() {
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let ls = WeakList::new();
+        let a = ls.new_elem(S { value: 1, buf: buf.clone() });
+        let w = Handle::downgrade(&a);
+        let b = a.clone();
+
+        // Shared: nothing happens.
+        let a = Handle::try_unwrap(a).err().unwrap();
+        drop(b);
+        assert_eq!(Handle::strong_count(&a), 1);
+        assert_eq!(ls.len(), 1);
+
+        // Unique: the value is moved out without being dropped.
+        let s = Handle::try_unwrap(a).ok().unwrap();
+        assert!(ls.is_empty());
+        assert!(w.upgrade().is_none());
+        assert!(buf.borrow().is_empty());
+        drop(s);
+        assert_eq!(*buf.borrow(), [1]);
+
+        // Unique by dropping.
+        let a = ls.new_elem(S { value: 2, buf: buf.clone() });
+        drop(a.clone());
+        assert_eq!(*buf.borrow(), [1]);
+        drop(a);
+        assert_eq!(*buf.borrow(), [1, 2]);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn shrink_pool_to_test() {
+        let ls = WeakList::with_capacity(5);
+        let _a = ls.new_elem(1);
+        ls.shrink_pool_to(6);
+        assert_eq!(ls.pool_capacity(), 4);
+        ls.shrink_pool_to(2);
+        assert_eq!(ls.pool_capacity(), 2);
+        ls.shrink_pool_to(0);
+        assert_eq!(ls.pool_capacity(), 0);
+        assert_eq!(values(&ls), [1]);
+    }
+
+    #[test]
+    fn swap_with_test() {
+        let (a, b) = (WeakList::new(), WeakList::new());
+        let _v: Vec<_> = (0..3).map(|i| a.new_elem(i)).collect();
+        let w = b.new_elem(10);
+        a.swap_with(&b);
+        assert_eq!(values(&a), [10]);
+        assert_eq!(values(&b), [2, 1, 0]);
+        assert_consistent(&a);
+        assert_consistent(&b);
+        drop(w);
+        assert!(a.is_empty());
+
+        a.swap_with(&b);
+        assert_eq!(values(&a), [2, 1, 0]);
+        assert!(b.is_empty());
+        assert_consistent(&a);
+        a.swap_with(&a);
+        assert_eq!(values(&a), [2, 1, 0]);
+    }
+
+    #[test]
+    fn count_where_test() {
+        let ls = WeakList::new();
+        assert_eq!(ls.count_where(|_| true), 0);
+        let _v: Vec<_> = (0..7).map(|i| ls.new_elem(i)).collect();
+        assert_eq!(ls.count_where(|&x| x % 3 == 1), 2);
+        assert_eq!(ls.count_where(|&x| x < 5), ls.iter().filter(|h| **h < 5).count());
+    }
+
+    #[test]
+    fn handle_map_test() {
+        let ls = WeakList::new();
+        let a = ls.new_elem((1, Some("x".to_owned())));
+        let m = Handle::map(a.clone(), |v| &v.0);
+        assert_eq!(*m, 1);
+        let m2 = Handle::try_map(a.clone(), |v| v.1.as_deref().ok_or(())).ok().unwrap();
+        assert_eq!(&*m2, "x");
+        drop(a);
+        // Projections keep the value alive.
+        assert_eq!(ls.len(), 1);
+        assert_eq!(Handle::strong_count(HandleMap::handle(&m)), 2);
+
+        let b = ls.new_elem((2, None));
+        let (b, e) = Handle::try_map(b, |v| v.1.as_deref().ok_or("none")).err().unwrap();
+        assert_eq!(e, "none");
+        assert_eq!(Handle::strong_count(&b), 1);
+        assert_eq!(ls.len(), 2);
+
+        let a = HandleMap::into_handle(m);
+        drop(m2);
+        assert_eq!(Handle::try_unwrap(a).ok(), Some((1, Some("x".to_owned()))));
+        drop(b);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn drain_rev_test() {
+        let ls = WeakList::new();
+        let _v: Vec<_> = (0..4).map(|i| ls.new_elem(i)).collect();
+        let mut it = ls.drain_rev();
+        assert_eq!(it.next().map(|h| *h), Some(0));
+        assert_eq!(it.next().map(|h| *h), Some(1));
+        drop(it);
+        assert_eq!(values(&ls), [3, 2]);
+        assert_consistent(&ls);
+        assert_eq!(ls.drain_rev().map(|h| *h).collect::<Vec<_>>(), [2, 3]);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn pop_front_back_test() {
+        let ls = WeakList::new();
+        let mut v = Vec::new();
+        v.push(ls.new_elem(1));
+        v.extend(ls.extend_back(Some(2)));
+        v.push(ls.new_elem(0));
+        v.extend(ls.extend_back(Some(3)));
+        assert_eq!(values(&ls), [0, 1, 2, 3]);
+
+        assert_eq!(ls.pop_back().map(|h| *h), Some(3));
+        assert_eq!(ls.pop_front().map(|h| *h), Some(0));
+        v.push(ls.new_elem(4));
+        assert_eq!(values(&ls), [4, 1, 2]);
+        assert_consistent(&ls);
+        let h = ls.pop_back().unwrap();
+        assert!(!Handle::is_linked(&h));
+        drop(h);
+        drop(v);
+        assert!(ls.is_empty());
+        assert!(ls.pop_front().is_none());
+        assert!(ls.pop_back().is_none());
+    }
+
+    #[test]
+    fn retain_no_drop_test() {
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let ls = WeakList::new();
+        let a = ls.new_elem(S { value: 1, buf: buf.clone() });
+        let b = ls.new_elem(S { value: 2, buf: buf.clone() });
+        assert_eq!(Handle::strong_count(&a), 1);
+        assert_eq!(ls.retain(|s| s.value != 1), 1);
+        // Only the weak reference is removed, `a` still owns the value.
+        assert!(buf.borrow().is_empty());
+        assert!(!Handle::is_linked(&a));
+        assert_eq!(Handle::strong_count(&a), 1);
+        assert_eq!(a.value, 1);
+        drop(a);
+        assert_eq!(*buf.borrow(), [1]);
+        drop(b);
+        assert_eq!(*buf.borrow(), [1, 2]);
+    }
+
+    #[test]
+    fn handles_matching_test() {
+        let ls = WeakList::new();
+        let v = RefCell::new((0..5).map(|i| ls.new_elem(i)).collect::<Vec<_>>());
+        let m = ls.handles_matching(|&x| {
+            let mut v = v.borrow_mut();
+            let i = v.iter().position(|h| **h == x).unwrap();
+            // The visited value is upgraded, so dropping its last `Handle`
+            // outside does not drop it.
+            assert_eq!(Handle::strong_count(&v[i]), 2);
+            if x == 4 {
+                v.remove(i);
+            }
+            x % 2 == 0
+        });
+        assert_eq!(m.iter().map(|h| **h).collect::<Vec<_>>(), [4, 2, 0]);
+        assert_eq!(Handle::strong_count(&m[0]), 1);
+        let counts: Vec<_> = v.borrow().iter().map(Handle::strong_count).collect();
+        assert_eq!(counts, [2, 1, 2, 1]);
+        assert_eq!(values(&ls), [4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn retain_range_test() {
+        let make = || {
+            let ls = WeakList::new();
+            let v = ls.extend_back(0..6);
+            (ls, v)
+        };
+
+        let (ls, _v) = make();
+        assert_eq!(ls.retain_range(|&x| x == 1, |&x| x == 3), 3);
+        assert_eq!(values(&ls), [1, 2, 3]);
+        assert_consistent(&ls);
+
+        let (ls, _v) = make();
+        assert_eq!(ls.retain_range(|&x| x == 2, |&x| x == 2), 5);
+        assert_eq!(values(&ls), [2]);
+
+        let (ls, _v) = make();
+        assert_eq!(ls.retain_range(|&x| x == 0, |&x| x == 4), 1);
+        assert_eq!(values(&ls), [0, 1, 2, 3, 4]);
+
+        let (ls, _v) = make();
+        assert_eq!(ls.retain_range(|&x| x == 4, |_| false), 4);
+        assert_eq!(values(&ls), [4, 5]);
+
+        let (ls, _v) = make();
+        assert_eq!(ls.retain_range(|_| false, |_| true), 6);
+        assert!(ls.is_empty());
+    }
+
+    #[test]
+    fn weak_all_test() {
+        let ls = WeakList::new();
+        let v: Vec<_> = (0..3).map(|i| ls.new_elem(i)).collect();
+        let w = ls.weak_all();
+        assert_eq!(w.iter().map(|w| *w.upgrade().unwrap()).collect::<Vec<_>>(), [2, 1, 0]);
+        assert!(v.iter().all(|h| Handle::strong_count(h) == 1 && Handle::weak_count(h) == 1));
+
+        ls.clear();
+        drop(ls);
+        assert!(w.iter().all(|w| w.upgrade().is_some()));
+        drop(v);
+        assert!(w.iter().all(|w| w.upgrade().is_none()));
+    }
+
+    #[test]
+    fn iter_moved_to_other_list_test() {
+        let (a, b) = (WeakList::new(), WeakList::new());
+        let v = a.extend_back(vec![1, 2, 3]);
+        let mut it = a.iter();
+        assert_eq!(it.next().map(|h| *h), Some(1));
+        // The iterator holds `2`, but does not follow it into `b`.
+        b.attach(&v[1]);
+        assert_eq!(it.next().map(|h| *h), None);
+        assert_eq!(values(&a), [1, 3]);
+        assert_eq!(values(&b), [2]);
+        assert_consistent(&a);
+        assert_consistent(&b);
+
+        let (c, d) = a.split_at(1);
+        assert_eq!(c.iter().map(|h| *h).collect::<Vec<_>>(), [1]);
+        assert_eq!(d.iter().map(|h| *h).collect::<Vec<_>>(), [3]);
+        assert_consistent(&c);
+        assert_consistent(&d);
+    }
+
+    #[test]
+    fn retain_detach_next_test() {
+        let ls = WeakList::new();
+        let v = ls.extend_back(vec![1, 2, 3, 4]);
+        let removed = ls.retain(|&x| {
+            if x == 1 {
+                Handle::detach(&v[1]);
+            }
+            false
+        });
+        assert_eq!(removed, 3);
+        assert!(ls.is_empty());
+
+        let v = ls.extend_back(vec![1, 2, 3, 4, 5]);
+        let removed = ls.retain(|&x| {
+            match x {
+                1 => Handle::detach(&v[1]),
+                3 => Handle::detach(&v[0]),
+                _ => {}
+            }
+            x == 1 || x == 5
+        });
+        // `2` and `1` are removed by `f`, and `3` and `4` by `retain`.
+        assert_eq!(removed, 2);
+        assert_eq!(values(&ls), [5]);
+        assert_consistent(&ls);
+    }
+
+    #[test]
+    fn retain_moved_to_other_list_test() {
+        let (a, b) = (WeakList::new(), WeakList::new());
+        let v = a.extend_back(vec![1, 2, 3]);
+        let _w = b.extend_back(vec![100, 101]);
+        let removed = a.retain(|&x| {
+            if x == 1 {
+                b.attach(&v[1]);
+            }
+            x != 3
+        });
+        assert_eq!(removed, 1);
+        assert_eq!(values(&a), [1]);
+        assert_eq!(values(&b), [2, 100, 101]);
+
+        // Values moved into another list by `f` are not removed from there.
+        let removed = a.retain(|_| {
+            b.attach(&v[0]);
+            false
+        });
+        assert_eq!(removed, 0);
+        assert!(a.is_empty());
+        assert_eq!(values(&b), [1, 2, 100, 101]);
+        assert_consistent(&a);
+        assert_consistent(&b);
+    }
+
+    #[test]
+    fn extract_if_other_list_test() {
+        let (a, b) = (WeakList::new(), WeakList::new());
+        let v = a.extend_back(vec![1, 2, 3]);
+        let _w = b.extend_back(vec![100, 101]);
+        let out: Vec<_> = a.extract_if(|&x| {
+            if x == 1 {
+                b.attach(&v[1]);
+            }
+            true
+        }).map(|h| *h).collect();
+        // `2` is skipped, and stays in `b`.
+        assert_eq!(out, [1, 3]);
+        assert!(a.is_empty());
+        assert_eq!(values(&b), [2, 100, 101]);
+
+        // A value moved out by `pred` is not removed from the other list.
+        a.attach(&v[0]);
+        let out: Vec<_> = a.extract_if(|_| {
+            b.attach(&v[0]);
+            true
+        }).map(|h| *h).collect();
+        assert!(out.is_empty());
+        assert_eq!(values(&b), [1, 2, 100, 101]);
+        assert_consistent(&a);
+        assert_consistent(&b);
+    }
+
+    #[test]
+    fn split_at_settings_test() {
+        let ls = WeakList::with_order(InsertOrder::Back);
+        let _h = ls.extend_back(vec![1, 2, 3]);
+        let (_a, b) = ls.split_at(1);
+        let _h4 = b.new_elem(4);
+        assert_eq!(values(&b), [2, 3, 4]);
+
+        let ls = WeakList::with_max_len(3);
+        let _h = ls.extend_back(vec![1, 2, 3]);
+        let (_a, b) = ls.split_at(1);
+        let _h4 = b.new_elem(4);
+        let _h5 = b.new_elem(5);
+        assert_eq!(values(&b), [5, 4, 2]);
+        assert_consistent(&b);
+    }
+
+    #[test]
+    fn max_len_extend_test() {
+        let ls = WeakList::with_max_len(3);
+        let a = ls.extend_back(vec![1, 2]);
+        // Older values make room, so the new ones are kept.
+        let _b = ls.extend_back(vec![3, 4]);
+        assert_eq!(values(&ls), [1, 3, 4]);
+        let _c = ls.extend_front(vec![5, 6]);
+        assert_eq!(values(&ls), [6, 5, 1]);
+        assert_consistent(&ls);
+
+        // Extra values are evicted from the same end as the oldest.
+        let d = ls.extend_back(vec![7, 8, 9, 10]);
+        assert_eq!(values(&ls), [7, 8, 9]);
+        let _e = ls.extend_front(vec![11, 12, 13, 14]);
+        assert_eq!(values(&ls), [14, 13, 12]);
+        assert_consistent(&ls);
+        // Evicted values are still alive.
+        let evicted = a.iter().chain(&d).map(|h| **h).collect::<Vec<_>>();
+        assert_eq!(evicted, [1, 2, 7, 8, 9, 10]);
+
+        let ls = WeakList::with_order_and_max_len(InsertOrder::Back, 3);
+        let _f = ls.extend_back(vec![1, 2, 3]);
+        let _g = ls.extend_back(vec![4]);
+        assert_eq!(values(&ls), [2, 3, 4]);
+        let _h = ls.extend_front(vec![5]);
+        assert_eq!(values(&ls), [5, 3, 4]);
+        assert_consistent(&ls);
     }
 }